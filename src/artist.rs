@@ -0,0 +1,286 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::array::{
+    LargeStringBuilder, ListBuilder, StringBuilder, StringDictionaryBuilder, StructBuilder,
+    UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Int8Type, Schema};
+use arrow::record_batch::RecordBatch;
+
+use crate::common::{
+    artist_ref_fields, artist_refs_builder, data_quality_builder, parse_artist_refs, parse_images,
+    parse_text_list,
+};
+use crate::error::{integer, ProcessingError};
+use crate::output::OutputOptions;
+use crate::reader::{EventExt, EventReader};
+use crate::sink::{self, Sink};
+
+struct ArtistBatchWriter {
+    writer: Box<dyn Sink>,
+    pending: usize,
+    batch_size: usize,
+    id: UInt32Builder,
+    name: StringBuilder,
+    realname: StringBuilder,
+    profile: LargeStringBuilder,
+    data_quality: StringDictionaryBuilder<Int8Type>,
+    urls: ListBuilder<StringBuilder>,
+    namevariations: ListBuilder<StringBuilder>,
+    aliases: ListBuilder<StructBuilder>,
+    members: ListBuilder<StructBuilder>,
+    groups: ListBuilder<StructBuilder>,
+    schema: Arc<Schema>,
+}
+
+impl ArtistBatchWriter {
+    fn new(output_file_path: &str, options: &OutputOptions) -> Result<Self, ProcessingError> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::UInt32, false),
+            Field::new("name", DataType::Utf8, false),
+            Field::new("realname", DataType::Utf8, true),
+            Field::new("profile", DataType::LargeUtf8, true),
+            Field::new(
+                "data_quality",
+                DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+                true,
+            ),
+            Field::new_list("urls", Field::new("item", DataType::Utf8, true), false),
+            Field::new_list(
+                "namevariations",
+                Field::new("item", DataType::Utf8, true),
+                false,
+            ),
+            Field::new_list(
+                "aliases",
+                Field::new_struct("item", artist_ref_fields(), true),
+                false,
+            ),
+            Field::new_list(
+                "members",
+                Field::new_struct("item", artist_ref_fields(), true),
+                false,
+            ),
+            Field::new_list(
+                "groups",
+                Field::new_struct("item", artist_ref_fields(), true),
+                false,
+            ),
+        ]));
+
+        let writer = sink::open(
+            Path::new(output_file_path),
+            "artists",
+            schema.clone(),
+            options,
+        )?;
+
+        Ok(ArtistBatchWriter {
+            writer,
+            pending: 0,
+            batch_size: options.batch_size,
+            id: UInt32Builder::with_capacity(options.batch_size),
+            name: StringBuilder::with_capacity(options.batch_size, 64),
+            realname: StringBuilder::new(),
+            profile: LargeStringBuilder::new(),
+            data_quality: data_quality_builder(),
+            urls: ListBuilder::new(StringBuilder::new()),
+            namevariations: ListBuilder::new(StringBuilder::new()),
+            aliases: artist_refs_builder(),
+            members: artist_refs_builder(),
+            groups: artist_refs_builder(),
+            schema,
+        })
+    }
+
+    fn push_id(&mut self, id: u32) {
+        self.id.append_value(id);
+    }
+
+    fn push_name(&mut self, name: &str) {
+        self.name.append_value(name);
+    }
+
+    fn push_realname(&mut self, realname: Option<&str>) {
+        self.realname.append_option(realname);
+    }
+
+    fn push_profile(&mut self, profile: Option<&str>) {
+        self.profile.append_option(profile);
+    }
+
+    fn push_data_quality(&mut self, data_quality: Option<&str>) {
+        match data_quality {
+            Some(data_quality) => self.data_quality.append_value(data_quality),
+            None => self.data_quality.append_null(),
+        }
+    }
+
+    fn write_artist(&mut self) -> Result<(), ProcessingError> {
+        // Mark end of current artist in list builders
+        self.urls.append(true);
+        self.namevariations.append(true);
+        self.aliases.append(true);
+        self.members.append(true);
+        self.groups.append(true);
+
+        self.pending += 1;
+
+        if self.pending == self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ProcessingError> {
+        if self.pending > 0 {
+            self.writer.write(&RecordBatch::try_new(
+                self.schema.clone(),
+                vec![
+                    Arc::new(self.id.finish()),
+                    Arc::new(self.name.finish()),
+                    Arc::new(self.realname.finish()),
+                    Arc::new(self.profile.finish()),
+                    Arc::new(self.data_quality.finish()),
+                    Arc::new(self.urls.finish()),
+                    Arc::new(self.namevariations.finish()),
+                    Arc::new(self.aliases.finish()),
+                    Arc::new(self.members.finish()),
+                    Arc::new(self.groups.finish()),
+                ],
+            )?)?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<(), ProcessingError> {
+        self.flush()?;
+        self.writer.close()
+    }
+}
+
+pub fn write_artists(
+    reader: &mut EventReader,
+    output_file_path: &str,
+    options: &OutputOptions,
+) -> Result<(), ProcessingError> {
+    let mut writer = ArtistBatchWriter::new(output_file_path, options)?;
+
+    reader.advance()?.expect_new_line()?;
+
+    let mut n: u128 = 0;
+
+    loop {
+        n += 1;
+        reader.set_record_id(None);
+        let event = reader.advance()?;
+
+        if event.is_end_of("artists") {
+            break;
+        }
+
+        event.expect_start_of("artist")?;
+
+        parse_artist(reader, &mut writer)?;
+
+        writer.write_artist()?;
+
+        if n.is_multiple_of(10_000) {
+            println!("{n}");
+        }
+    }
+    writer.close()
+}
+
+fn parse_artist(
+    reader: &mut EventReader,
+    writer: &mut ArtistBatchWriter,
+) -> Result<(), ProcessingError> {
+    let mut has_id = false;
+    let mut has_name = false;
+    let mut has_realname = false;
+    let mut has_profile = false;
+    let mut has_data_quality = false;
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("artist") {
+            break;
+        }
+
+        // Empty fields are filled in with nulls below
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        let event = event.expect_start()?;
+
+        // We can't assume the order of elements within an artist
+        match event.name().into_inner() {
+            b"images" => parse_images(reader)?,
+            b"id" => {
+                let id = reader
+                    .read_text("id")?
+                    .ok_or(ProcessingError::expected_text())?;
+                let id = integer(id)?;
+                reader.set_record_id(Some(id));
+                writer.push_id(id);
+                has_id = true;
+            }
+            b"name" => {
+                let name = reader
+                    .read_text("name")?
+                    .ok_or(ProcessingError::expected_text())?;
+                writer.push_name(name);
+                has_name = true;
+            }
+            b"realname" => {
+                let realname = reader.read_text("realname")?;
+                writer.push_realname(realname);
+                has_realname = true;
+            }
+            b"profile" => {
+                let profile = reader.read_text("profile")?;
+                writer.push_profile(profile);
+                has_profile = true;
+            }
+            b"data_quality" => {
+                let data_quality = reader.read_text("data_quality")?;
+                writer.push_data_quality(data_quality);
+                has_data_quality = true;
+            }
+            b"urls" => parse_text_list(reader, "urls", "url", &mut writer.urls)?,
+            b"namevariations" => {
+                parse_text_list(reader, "namevariations", "name", &mut writer.namevariations)?
+            }
+            b"aliases" => parse_artist_refs(reader, "aliases", &mut writer.aliases)?,
+            b"members" => parse_artist_refs(reader, "members", &mut writer.members)?,
+            b"groups" => parse_artist_refs(reader, "groups", &mut writer.groups)?,
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+
+    // Every artist has an id and a name, so there's nothing to fill them in with
+    if !has_id {
+        return Err(ProcessingError::missing_field("id"));
+    }
+    if !has_name {
+        return Err(ProcessingError::missing_field("name"));
+    }
+    if !has_realname {
+        writer.push_realname(None);
+    }
+    if !has_profile {
+        writer.push_profile(None);
+    }
+    if !has_data_quality {
+        writer.push_data_quality(None);
+    }
+
+    reader.advance()?.expect_new_line()?;
+
+    Ok(())
+}
@@ -0,0 +1,684 @@
+//! Columns that are shared between the different Discogs dumps, along with
+//! the builders and parsers that fill them.
+
+use quick_xml::events::{attributes::Attribute, BytesStart};
+use quick_xml::name::QName;
+
+use arrow::array::{
+    ArrayBuilder, BooleanBuilder, ListBuilder, StringArray, StringBuilder, StringDictionaryBuilder,
+    StructBuilder, UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Fields, Int8Type};
+
+use std::collections::BTreeSet;
+
+use crate::error::{integer, unescape, unescape_value, OnDuplicate, ProcessingError};
+use crate::reader::{EventExt, EventReader};
+
+const DATA_QUALITIES: [&str; 7] = [
+    "Correct",
+    "Complete and Correct",
+    "Needs Vote",
+    "Needs Minor Changes",
+    "Needs Major Changes",
+    "Entirely Incorrect",
+    "Entirely Incorrect Edit",
+];
+
+// Field positions within the artist struct
+const ARTIST_ID: usize = 0;
+const ARTIST_NAME: usize = 1;
+const ARTIST_ANV: usize = 2;
+const ARTIST_JOIN: usize = 3;
+
+// Field positions within the extra artist struct
+const EXTRA_ARTIST_ID: usize = 0;
+const EXTRA_ARTIST_NAME: usize = 1;
+const EXTRA_ARTIST_ANV: usize = 2;
+const EXTRA_ARTIST_ROLE: usize = 3;
+const EXTRA_ARTIST_TRACKS: usize = 4;
+
+// Field positions within the video struct
+const VIDEO_SRC: usize = 0;
+const VIDEO_DURATION: usize = 1;
+const VIDEO_EMBED: usize = 2;
+const VIDEO_TITLE: usize = 3;
+const VIDEO_DESCRIPTION: usize = 4;
+
+/// A value that should only be given once in a record, or in a struct within
+/// it. Values are held back until the end of the record or struct, so that a
+/// repeated element can replace an earlier one, and so that those left out can
+/// be filled in with nulls.
+pub struct Single<T> {
+    filled: bool,
+    pub value: Option<T>,
+}
+
+impl<T> Default for Single<T> {
+    fn default() -> Self {
+        Single {
+            filled: false,
+            value: None,
+        }
+    }
+}
+
+impl<T> Single<T> {
+    /// Returns whether it had been filled already.
+    fn fill(&mut self, value: Option<T>, on_duplicate: OnDuplicate) -> bool {
+        let repeated = self.filled;
+        if !repeated || on_duplicate == OnDuplicate::KeepLast {
+            self.value = value;
+        }
+        self.filled = true;
+        repeated
+    }
+}
+
+/// What to do with repeated elements, and which have been repeated in the
+/// record being parsed.
+pub struct Repeated {
+    pub on_duplicate: OnDuplicate,
+    pub names: BTreeSet<&'static str>,
+}
+
+impl Repeated {
+    pub fn new(on_duplicate: OnDuplicate) -> Self {
+        Repeated {
+            on_duplicate,
+            names: BTreeSet::new(),
+        }
+    }
+
+    /// Fill in a single value, erroring if it's repeated and that's the policy.
+    /// Nested elements are named by their struct too, e.g. `track/title`.
+    pub fn fill<T>(
+        &mut self,
+        name: &'static str,
+        single: &mut Single<T>,
+        value: Option<T>,
+    ) -> Result<(), ProcessingError> {
+        if single.fill(value, self.on_duplicate) {
+            if self.on_duplicate == OnDuplicate::Error {
+                return Err(ProcessingError::duplicate_element(name));
+            }
+            self.names.insert(name);
+        }
+        Ok(())
+    }
+}
+
+pub fn artist_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("id", DataType::UInt32, false),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("anv", DataType::Utf8, true),
+        Field::new("join", DataType::Utf8, true),
+    ])
+}
+
+pub fn extra_artist_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("id", DataType::UInt32, false),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("anv", DataType::Utf8, true),
+        Field::new("role", DataType::Utf8, true),
+        Field::new("tracks", DataType::Utf8, true),
+    ])
+}
+
+pub fn video_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("src", DataType::Utf8, true),
+        Field::new("duration", DataType::UInt32, true),
+        Field::new("embed", DataType::Boolean, true),
+        Field::new("title", DataType::Utf8, true),
+        Field::new("description", DataType::Utf8, true),
+    ])
+}
+
+pub fn artists_builder() -> ListBuilder<StructBuilder> {
+    ListBuilder::new(StructBuilder::new(
+        artist_fields(),
+        vec![
+            Box::new(UInt32Builder::new()), // id
+            Box::new(StringBuilder::new()), // name
+            Box::new(StringBuilder::new()), // anv
+            Box::new(StringBuilder::new()), // join
+        ],
+    ))
+}
+
+pub fn extra_artists_builder() -> ListBuilder<StructBuilder> {
+    ListBuilder::new(StructBuilder::new(
+        extra_artist_fields(),
+        vec![
+            Box::new(UInt32Builder::new()), // id
+            Box::new(StringBuilder::new()), // name
+            Box::new(StringBuilder::new()), // anv
+            Box::new(StringBuilder::new()), // role
+            Box::new(StringBuilder::new()), // tracks
+        ],
+    ))
+}
+
+pub fn videos_builder() -> ListBuilder<StructBuilder> {
+    ListBuilder::new(StructBuilder::new(
+        video_fields(),
+        vec![
+            Box::new(StringBuilder::new()),  // src
+            Box::new(UInt32Builder::new()),  // duration
+            Box::new(BooleanBuilder::new()), // embed
+            Box::new(StringBuilder::new()),  // title
+            Box::new(StringBuilder::new()),  // description
+        ],
+    ))
+}
+
+pub fn data_quality_builder() -> StringDictionaryBuilder<Int8Type> {
+    let data_quality_values = StringArray::from(DATA_QUALITIES.to_vec());
+    StringDictionaryBuilder::<Int8Type>::new_with_dictionary(
+        DATA_QUALITIES.len(),
+        &data_quality_values,
+    )
+    .unwrap()
+}
+
+pub fn struct_field<T: ArrayBuilder>(builder: &mut StructBuilder, i: usize) -> &mut T {
+    builder.field_builder::<T>(i).unwrap()
+}
+
+/// Whether each field of a struct, and of the structs in its lists, has a value
+/// for every row. A parser that misses a field leaves it short, which would
+/// otherwise only come out as a panic when the batch is finished.
+pub fn is_aligned(builder: &mut StructBuilder) -> bool {
+    let len = builder.len();
+    (0..builder.num_fields()).all(|i| {
+        if let Some(list) = builder.field_builder::<ListBuilder<StructBuilder>>(i) {
+            list.len() == len && is_aligned(list.values())
+        } else if let Some(list) = builder.field_builder::<ListBuilder<StringBuilder>>(i) {
+            list.len() == len
+        } else if let Some(field) = builder.field_builder::<StringBuilder>(i) {
+            field.len() == len
+        } else if let Some(field) = builder.field_builder::<UInt32Builder>(i) {
+            field.len() == len
+        } else if let Some(field) = builder.field_builder::<BooleanBuilder>(i) {
+            field.len() == len
+        } else {
+            unreachable!("structs only have fields of the types above")
+        }
+    })
+}
+
+pub fn parse_artists(
+    reader: &mut EventReader,
+    artists: &mut ListBuilder<StructBuilder>,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("artists") {
+            break Ok(());
+        }
+
+        event.expect_start_of("artist")?;
+
+        parse_artist(reader, artists.values(), repeated)?;
+    }
+}
+
+fn parse_artist(
+    reader: &mut EventReader,
+    artist: &mut StructBuilder,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    let mut id = Single::default();
+    let mut name = Single::default();
+    let mut anv = Single::default();
+    let mut join = Single::default();
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("artist") {
+            break;
+        }
+
+        let event = event.expect_start()?;
+
+        match event.name().into_inner() {
+            b"id" => {
+                // Id should never be null
+                let event = reader.advance()?;
+                let value = event.expect_text()?;
+                let value = integer(&unescape(&value)?)?;
+                repeated.fill("artist/id", &mut id, Some(value))?;
+                reader.advance()?.expect_end_of("id")?;
+            }
+            b"name" => {
+                // Name should never be null
+                let event = reader.advance()?;
+                let value = event.expect_text()?;
+                let value = unescape(&value)?.into_owned();
+                repeated.fill("artist/name", &mut name, Some(value))?;
+                reader.advance()?.expect_end_of("name")?;
+            }
+            b"anv" => {
+                // Artist name variation can be null
+                let event = reader.advance()?;
+                if event.is_end_of("anv") {
+                    repeated.fill("artist/anv", &mut anv, None)?;
+                } else {
+                    let value = event.expect_text()?;
+                    let value = unescape(&value)?.into_owned();
+                    repeated.fill("artist/anv", &mut anv, Some(value))?;
+                    reader.advance()?.expect_end_of("anv")?;
+                }
+            }
+            b"join" => {
+                // Join field can be null
+                let event = reader.advance()?;
+                if event.is_end_of("join") {
+                    repeated.fill("artist/join", &mut join, None)?;
+                } else {
+                    let value = event.expect_text()?;
+                    let value = unescape(&value)?.into_owned();
+                    repeated.fill("artist/join", &mut join, Some(value))?;
+                    reader.advance()?.expect_end_of("join")?;
+                }
+            }
+            b"role" => {
+                // Tracks never seems to hold a value for main artist, so we can skip
+                reader.advance()?.expect_end_of("role")?;
+            }
+            b"tracks" => {
+                // Tracks never seems to hold a value for main artist, so we can skip
+                reader.advance()?.expect_end_of("tracks")?;
+            }
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+
+    let id = id.value.ok_or(ProcessingError::missing_field("id"))?;
+    let name = name.value.ok_or(ProcessingError::missing_field("name"))?;
+
+    struct_field::<UInt32Builder>(artist, ARTIST_ID).append_value(id);
+    struct_field::<StringBuilder>(artist, ARTIST_NAME).append_value(name);
+    struct_field::<StringBuilder>(artist, ARTIST_ANV).append_option(anv.value);
+    struct_field::<StringBuilder>(artist, ARTIST_JOIN).append_option(join.value);
+    artist.append(true);
+
+    Ok(())
+}
+
+pub fn parse_images(reader: &mut EventReader) -> Result<(), ProcessingError> {
+    // Images are ignored because uris are not in the dataset
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("images") {
+            break Ok(());
+        }
+
+        event.expect_empty("image")?;
+    }
+}
+
+pub fn parse_genres(
+    reader: &mut EventReader,
+    genres: &mut ListBuilder<StringBuilder>,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("genres") {
+            break Ok(());
+        }
+
+        event.expect_start_of("genre")?;
+
+        let genre = reader.advance()?.expect_text()?;
+        let genre = unescape(&genre)?;
+        genres.values().append_value(genre);
+
+        reader.advance()?.expect_end_of("genre")?;
+    }
+}
+
+pub fn parse_styles(
+    reader: &mut EventReader,
+    styles: &mut ListBuilder<StringBuilder>,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("styles") {
+            break Ok(());
+        }
+
+        event.expect_start_of("style")?;
+
+        let style = reader.advance()?.expect_text()?;
+        let style = unescape(&style)?;
+        styles.values().append_value(style);
+
+        reader.advance()?.expect_end_of("style")?;
+    }
+}
+
+pub fn parse_extra_artists(
+    reader: &mut EventReader,
+    extra_artists: &mut ListBuilder<StructBuilder>,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("extraartists") {
+            break Ok(());
+        }
+
+        event.expect_start_of("artist")?;
+
+        parse_extra_artist(reader, extra_artists.values(), repeated)?;
+    }
+}
+
+fn parse_extra_artist(
+    reader: &mut EventReader,
+    extra_artist: &mut StructBuilder,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    let mut id = Single::default();
+    let mut name = Single::default();
+    let mut anv = Single::default();
+    let mut role = Single::default();
+    let mut tracks = Single::default();
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("artist") {
+            break;
+        }
+
+        let event = event.expect_start()?;
+
+        match event.name().into_inner() {
+            b"id" => {
+                // Id should never be null
+                let value = reader
+                    .read_text("id")?
+                    .ok_or(ProcessingError::expected_text())?;
+                let value = integer(value)?;
+                repeated.fill("extraartists/artist/id", &mut id, Some(value))?;
+            }
+            b"name" => {
+                // Name should never be null
+                let value = reader
+                    .read_text("name")?
+                    .ok_or(ProcessingError::expected_text())?;
+                let value = value.to_owned();
+                repeated.fill("extraartists/artist/name", &mut name, Some(value))?;
+            }
+            b"anv" => {
+                let value = reader.read_text("anv")?.map(str::to_owned);
+                repeated.fill("extraartists/artist/anv", &mut anv, value)?;
+            }
+            b"join" => {
+                // Joins only make sense between main artists, so we can skip
+                reader.skip_to_end_of("join")?;
+            }
+            b"role" => {
+                let value = reader.read_text("role")?.map(str::to_owned);
+                repeated.fill("extraartists/artist/role", &mut role, value)?;
+            }
+            b"tracks" => {
+                let value = reader.read_text("tracks")?.map(str::to_owned);
+                repeated.fill("extraartists/artist/tracks", &mut tracks, value)?;
+            }
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+
+    // A credit is no use without knowing who it's for
+    let id = id.value.ok_or(ProcessingError::missing_field("id"))?;
+    let name = name.value.ok_or(ProcessingError::missing_field("name"))?;
+
+    struct_field::<UInt32Builder>(extra_artist, EXTRA_ARTIST_ID).append_value(id);
+    struct_field::<StringBuilder>(extra_artist, EXTRA_ARTIST_NAME).append_value(name);
+    struct_field::<StringBuilder>(extra_artist, EXTRA_ARTIST_ANV).append_option(anv.value);
+    struct_field::<StringBuilder>(extra_artist, EXTRA_ARTIST_ROLE).append_option(role.value);
+    struct_field::<StringBuilder>(extra_artist, EXTRA_ARTIST_TRACKS).append_option(tracks.value);
+    extra_artist.append(true);
+
+    Ok(())
+}
+
+pub fn parse_videos(
+    reader: &mut EventReader,
+    videos: &mut ListBuilder<StructBuilder>,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("videos") {
+            break Ok(());
+        }
+
+        let is_empty = event.is_empty_tag();
+        let video_start = if is_empty {
+            event.expect_empty("video")?
+        } else {
+            event.expect_start_of("video")?
+        };
+
+        let video = videos.values();
+        parse_video_attributes(&video_start, video)?;
+        parse_video(reader, video, is_empty, repeated)?;
+
+        video.append(true);
+    }
+}
+
+fn parse_video(
+    reader: &mut EventReader,
+    video: &mut StructBuilder,
+    is_empty: bool,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    let mut title = Single::default();
+    let mut description = Single::default();
+
+    if !is_empty {
+        loop {
+            let event = reader.advance()?;
+
+            if event.is_end_of("video") {
+                break;
+            }
+
+            if event.is_empty_tag() {
+                continue;
+            }
+
+            let event = event.expect_start()?;
+
+            match event.name().into_inner() {
+                b"title" => {
+                    let value = reader.read_text("title")?.map(str::to_owned);
+                    repeated.fill("video/title", &mut title, value)?;
+                }
+                b"description" => {
+                    let value = reader.read_text("description")?.map(str::to_owned);
+                    repeated.fill("video/description", &mut description, value)?;
+                }
+                _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+            }
+        }
+    }
+
+    struct_field::<StringBuilder>(video, VIDEO_TITLE).append_option(title.value);
+    struct_field::<StringBuilder>(video, VIDEO_DESCRIPTION).append_option(description.value);
+
+    Ok(())
+}
+
+fn parse_video_attributes(
+    video_start: &BytesStart,
+    video: &mut StructBuilder,
+) -> Result<(), ProcessingError> {
+    let mut has_src = false;
+    let mut has_duration = false;
+    let mut has_embed = false;
+
+    for a in video_start.attributes() {
+        match a {
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"src"), ..
+                },
+            ) => {
+                let src = unescape_value(&attribute)?;
+                struct_field::<StringBuilder>(video, VIDEO_SRC).append_value(src);
+                has_src = true;
+            }
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"duration"),
+                    ..
+                },
+            ) => {
+                // Duration is given in seconds
+                let duration = integer(&unescape_value(&attribute)?)?;
+                struct_field::<UInt32Builder>(video, VIDEO_DURATION).append_value(duration);
+                has_duration = true;
+            }
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"embed"),
+                    ..
+                },
+            ) => {
+                let embed = match unescape_value(&attribute)?.as_ref() {
+                    "true" => true,
+                    "false" => false,
+                    embed => return Err(ProcessingError::invalid_boolean(embed.as_bytes())),
+                };
+                struct_field::<BooleanBuilder>(video, VIDEO_EMBED).append_value(embed);
+                has_embed = true;
+            }
+            Ok(Attribute { key, .. }) => {
+                return Err(ProcessingError::unknown_attribute(key.into_inner()))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    if !has_src {
+        struct_field::<StringBuilder>(video, VIDEO_SRC).append_null();
+    }
+    if !has_duration {
+        struct_field::<UInt32Builder>(video, VIDEO_DURATION).append_null();
+    }
+    if !has_embed {
+        struct_field::<BooleanBuilder>(video, VIDEO_EMBED).append_null();
+    }
+
+    Ok(())
+}
+
+// Field positions within the artist reference struct
+const ARTIST_REF_ID: usize = 0;
+const ARTIST_REF_NAME: usize = 1;
+
+/// Aliases, members and groups refer to other artists by id and name.
+pub fn artist_ref_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("id", DataType::UInt32, true),
+        Field::new("name", DataType::Utf8, false),
+    ])
+}
+
+pub fn artist_refs_builder() -> ListBuilder<StructBuilder> {
+    ListBuilder::new(StructBuilder::new(
+        artist_ref_fields(),
+        vec![
+            Box::new(UInt32Builder::new()), // id
+            Box::new(StringBuilder::new()), // name
+        ],
+    ))
+}
+
+pub fn parse_artist_refs(
+    reader: &mut EventReader,
+    end_of: &'static str,
+    artist_refs: &mut ListBuilder<StructBuilder>,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of(end_of) {
+            break Ok(());
+        }
+
+        let event = event.expect_start()?;
+
+        match event.name().into_inner() {
+            b"name" => {
+                let artist_ref = artist_refs.values();
+
+                let id = event.attributes().find_map(|a| match a {
+                    Ok(
+                        attribute @ Attribute {
+                            key: QName(b"id"), ..
+                        },
+                    ) => Some(attribute),
+                    _ => None,
+                });
+                let id = id.as_ref().map(unescape_value).transpose()?;
+                let id = id.as_deref().map(integer).transpose()?;
+                struct_field::<UInt32Builder>(artist_ref, ARTIST_REF_ID).append_option(id);
+
+                let name = reader
+                    .read_text("name")?
+                    .ok_or(ProcessingError::expected_text())?;
+                struct_field::<StringBuilder>(artist_ref, ARTIST_REF_NAME).append_value(name);
+                artist_ref.append(true);
+            }
+            b"id" => {
+                // Members list each id again ahead of the name, so we can skip
+                reader.skip_to_end_of("id")?;
+            }
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+}
+
+pub fn parse_text_list(
+    reader: &mut EventReader,
+    end_of: &'static str,
+    item: &'static str,
+    list: &mut ListBuilder<StringBuilder>,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of(end_of) {
+            break Ok(());
+        }
+
+        // Empty items carry no information, so we can skip
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        event.expect_start_of(item)?;
+
+        if let Some(text) = reader.read_text(item)? {
+            list.values().append_value(text);
+        }
+    }
+}
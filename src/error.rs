@@ -0,0 +1,341 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use quick_xml::events::attributes::{AttrError, Attribute};
+use quick_xml::events::BytesText;
+
+use crate::reader::EventReader;
+
+#[derive(Debug)]
+pub enum ProcessingError {
+    ExpectedStart(Position),
+    ExpectedStartOf(&'static str, Position),
+    ExpectedEndOf(&'static str, Position),
+    ExpectedEmpty(&'static str, Position),
+    ExpectedText(Position),
+    ExpectedNewline(Position),
+    ExpectedEof(Position),
+    UnknownDump(String),
+    UnknownElement(String, Position),
+    UnknownAttribute(String, Position),
+    MissingField(&'static str, Position),
+    DuplicateElement(&'static str, Position),
+    MisalignedColumn(&'static str, Position),
+    InvalidUtf8(Position),
+    InvalidEscape(String, Position),
+    InvalidInteger(String, Position),
+    InvalidBoolean(String, Position),
+    IoError(std::io::Error),
+    XMLParseError(quick_xml::Error),
+    ArrowError(arrow::error::ArrowError),
+    ParquetError(parquet::errors::ParquetError),
+    SqliteError(rusqlite::Error),
+}
+
+/// What to do with a record that can't be parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnError {
+    /// Stop the conversion
+    Fail,
+    /// Leave the record out, set it aside and carry on
+    Skip,
+}
+
+/// What to do with an element that should only appear once in a record, but
+/// is repeated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnDuplicate {
+    Error,
+    KeepFirst,
+    KeepLast,
+}
+
+/// Where in the dump an error was found.
+///
+/// Errors are raised deep inside the parsers, which only see the event they
+/// are looking at, so they start out with an empty position which is filled
+/// in by [`ProcessingError::locate`] on the way out.
+#[derive(Debug, Default)]
+pub struct Position {
+    /// Byte offset into the decompressed XML
+    pub offset: usize,
+    /// Id of the release, artist, label or master being parsed, if known yet
+    pub record_id: Option<u32>,
+    /// Path of the element being parsed, e.g. `releases/release/tracklist/track`
+    pub path: String,
+}
+
+impl ProcessingError {
+    pub fn expected_start() -> Self {
+        ProcessingError::ExpectedStart(Position::default())
+    }
+
+    pub fn expected_start_of(name: &'static str) -> Self {
+        ProcessingError::ExpectedStartOf(name, Position::default())
+    }
+
+    pub fn expected_end_of(name: &'static str) -> Self {
+        ProcessingError::ExpectedEndOf(name, Position::default())
+    }
+
+    pub fn expected_empty(name: &'static str) -> Self {
+        ProcessingError::ExpectedEmpty(name, Position::default())
+    }
+
+    pub fn expected_text() -> Self {
+        ProcessingError::ExpectedText(Position::default())
+    }
+
+    pub fn expected_newline() -> Self {
+        ProcessingError::ExpectedNewline(Position::default())
+    }
+
+    pub fn expected_eof() -> Self {
+        ProcessingError::ExpectedEof(Position::default())
+    }
+
+    pub fn unknown_element(name: &[u8]) -> Self {
+        ProcessingError::UnknownElement(
+            String::from_utf8_lossy(name).into_owned(),
+            Position::default(),
+        )
+    }
+
+    pub fn unknown_attribute(name: &[u8]) -> Self {
+        ProcessingError::UnknownAttribute(
+            String::from_utf8_lossy(name).into_owned(),
+            Position::default(),
+        )
+    }
+
+    pub fn missing_field(name: &'static str) -> Self {
+        ProcessingError::MissingField(name, Position::default())
+    }
+
+    pub fn duplicate_element(name: &'static str) -> Self {
+        ProcessingError::DuplicateElement(name, Position::default())
+    }
+
+    pub fn misaligned_column(name: &'static str) -> Self {
+        ProcessingError::MisalignedColumn(name, Position::default())
+    }
+
+    pub fn invalid_integer(value: &str) -> Self {
+        ProcessingError::InvalidInteger(value.to_owned(), Position::default())
+    }
+
+    pub fn invalid_boolean(value: &[u8]) -> Self {
+        ProcessingError::InvalidBoolean(
+            String::from_utf8_lossy(value).into_owned(),
+            Position::default(),
+        )
+    }
+
+    /// The name of the kind of error, for grouping errors in summaries.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProcessingError::ExpectedStart(_) => "ExpectedStart",
+            ProcessingError::ExpectedStartOf(_, _) => "ExpectedStartOf",
+            ProcessingError::ExpectedEndOf(_, _) => "ExpectedEndOf",
+            ProcessingError::ExpectedEmpty(_, _) => "ExpectedEmpty",
+            ProcessingError::ExpectedText(_) => "ExpectedText",
+            ProcessingError::ExpectedNewline(_) => "ExpectedNewline",
+            ProcessingError::ExpectedEof(_) => "ExpectedEof",
+            ProcessingError::UnknownDump(_) => "UnknownDump",
+            ProcessingError::UnknownElement(_, _) => "UnknownElement",
+            ProcessingError::UnknownAttribute(_, _) => "UnknownAttribute",
+            ProcessingError::MissingField(_, _) => "MissingField",
+            ProcessingError::DuplicateElement(_, _) => "DuplicateElement",
+            ProcessingError::MisalignedColumn(_, _) => "MisalignedColumn",
+            ProcessingError::InvalidUtf8(_) => "InvalidUtf8",
+            ProcessingError::InvalidEscape(_, _) => "InvalidEscape",
+            ProcessingError::InvalidInteger(_, _) => "InvalidInteger",
+            ProcessingError::InvalidBoolean(_, _) => "InvalidBoolean",
+            ProcessingError::IoError(_) => "IoError",
+            ProcessingError::XMLParseError(_) => "XMLParseError",
+            ProcessingError::ArrowError(_) => "ArrowError",
+            ProcessingError::ParquetError(_) => "ParquetError",
+            ProcessingError::SqliteError(_) => "SqliteError",
+        }
+    }
+
+    /// Whether the XML itself can still be read after this error, so that we
+    /// can carry on with the next record.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            ProcessingError::IoError(_)
+                | ProcessingError::XMLParseError(_)
+                | ProcessingError::ArrowError(_)
+                | ProcessingError::ParquetError(_)
+                | ProcessingError::SqliteError(_)
+        )
+    }
+
+    fn position_mut(&mut self) -> Option<&mut Position> {
+        match self {
+            ProcessingError::ExpectedStart(position)
+            | ProcessingError::ExpectedStartOf(_, position)
+            | ProcessingError::ExpectedEndOf(_, position)
+            | ProcessingError::ExpectedEmpty(_, position)
+            | ProcessingError::ExpectedText(position)
+            | ProcessingError::ExpectedNewline(position)
+            | ProcessingError::ExpectedEof(position)
+            | ProcessingError::UnknownElement(_, position)
+            | ProcessingError::UnknownAttribute(_, position)
+            | ProcessingError::MissingField(_, position)
+            | ProcessingError::DuplicateElement(_, position)
+            | ProcessingError::MisalignedColumn(_, position)
+            | ProcessingError::InvalidUtf8(position)
+            | ProcessingError::InvalidEscape(_, position)
+            | ProcessingError::InvalidInteger(_, position)
+            | ProcessingError::InvalidBoolean(_, position) => Some(position),
+            _ => None,
+        }
+    }
+
+    /// Fill in the position of the error, if it has one and it isn't known yet,
+    /// from where the reader has got to. This relies on errors being returned
+    /// straight away, before the reader moves on.
+    pub fn locate(mut self, reader: &EventReader) -> Self {
+        if let Some(position) = self.position_mut() {
+            // Any element we've located an error in has a path
+            if position.path.is_empty() {
+                *position = reader.position();
+            }
+        }
+        self
+    }
+}
+
+/// Decode text from the dump, replacing escapes such as `&amp;` with the
+/// characters they stand for.
+pub fn unescape<'a>(text: &'a BytesText) -> Result<Cow<'a, str>, ProcessingError> {
+    text.unescape().map_err(invalid_text)
+}
+
+/// Decode the value of an attribute, likewise.
+pub fn unescape_value<'a>(attribute: &Attribute<'a>) -> Result<Cow<'a, str>, ProcessingError> {
+    attribute.unescape_value().map_err(invalid_text)
+}
+
+fn invalid_text(err: quick_xml::Error) -> ProcessingError {
+    match err {
+        quick_xml::Error::NonDecodable(_) => ProcessingError::InvalidUtf8(Position::default()),
+        err => ProcessingError::InvalidEscape(err.to_string(), Position::default()),
+    }
+}
+
+/// Parse a number from the dump.
+pub fn integer<T: std::str::FromStr>(value: &str) -> Result<T, ProcessingError> {
+    value
+        .parse()
+        .map_err(|_| ProcessingError::invalid_integer(value))
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at byte {}", self.offset)?;
+        // Outside of the root element there's no path to speak of
+        if !self.path.is_empty() {
+            write!(f, " in <{}>", self.path)?;
+        }
+        if let Some(record_id) = self.record_id {
+            write!(f, " of record {record_id}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessingError::ExpectedStart(position) => {
+                write!(f, "expected start of an element {position}")
+            }
+            ProcessingError::ExpectedStartOf(name, position) => {
+                write!(f, "expected start of <{name}> {position}")
+            }
+            ProcessingError::ExpectedEndOf(name, position) => {
+                write!(f, "expected end of <{name}> {position}")
+            }
+            ProcessingError::ExpectedEmpty(name, position) => {
+                write!(f, "expected empty <{name}/> {position}")
+            }
+            ProcessingError::ExpectedText(position) => write!(f, "expected text {position}"),
+            ProcessingError::ExpectedNewline(position) => {
+                write!(f, "expected newline {position}")
+            }
+            ProcessingError::ExpectedEof(position) => write!(f, "expected end of file {position}"),
+            ProcessingError::UnknownDump(root) => {
+                write!(f, "<{root}> is not the root of a known Discogs dump")
+            }
+            ProcessingError::UnknownElement(name, position) => {
+                write!(f, "unknown element <{name}> {position}")
+            }
+            ProcessingError::UnknownAttribute(name, position) => {
+                write!(f, "unknown attribute {name:?} {position}")
+            }
+            ProcessingError::MissingField(name, position) => {
+                write!(f, "missing {name} {position}")
+            }
+            ProcessingError::DuplicateElement(name, position) => {
+                write!(f, "repeated <{name}> {position}")
+            }
+            ProcessingError::MisalignedColumn(name, position) => {
+                write!(f, "fields of {name} out of step {position}")
+            }
+            ProcessingError::InvalidUtf8(position) => write!(f, "invalid utf-8 {position}"),
+            ProcessingError::InvalidEscape(err, position) => write!(f, "{err} {position}"),
+            ProcessingError::InvalidInteger(value, position) => {
+                write!(f, "invalid integer {value:?} {position}")
+            }
+            ProcessingError::InvalidBoolean(value, position) => {
+                write!(f, "invalid boolean {value:?} {position}")
+            }
+            ProcessingError::IoError(err) => write!(f, "io error: {err}"),
+            ProcessingError::XMLParseError(err) => write!(f, "xml parse error: {err}"),
+            ProcessingError::ArrowError(err) => write!(f, "arrow error: {err}"),
+            ProcessingError::ParquetError(err) => write!(f, "parquet error: {err}"),
+            ProcessingError::SqliteError(err) => write!(f, "sqlite error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ProcessingError {}
+
+impl From<std::io::Error> for ProcessingError {
+    fn from(err: std::io::Error) -> Self {
+        ProcessingError::IoError(err)
+    }
+}
+
+impl From<quick_xml::Error> for ProcessingError {
+    fn from(err: quick_xml::Error) -> Self {
+        ProcessingError::XMLParseError(err)
+    }
+}
+
+impl From<arrow::error::ArrowError> for ProcessingError {
+    fn from(err: arrow::error::ArrowError) -> Self {
+        ProcessingError::ArrowError(err)
+    }
+}
+
+impl From<parquet::errors::ParquetError> for ProcessingError {
+    fn from(err: parquet::errors::ParquetError) -> Self {
+        ProcessingError::ParquetError(err)
+    }
+}
+
+impl From<rusqlite::Error> for ProcessingError {
+    fn from(err: rusqlite::Error) -> Self {
+        ProcessingError::SqliteError(err)
+    }
+}
+
+impl From<AttrError> for ProcessingError {
+    fn from(err: AttrError) -> Self {
+        ProcessingError::XMLParseError(err.into())
+    }
+}
@@ -0,0 +1,1001 @@
+//! Decompressing gzip on several threads. A deflate stream can't just be cut
+//! up, as blocks don't say where they start and refer back to the 32KB before
+//! them. So each worker takes a segment of the compressed input, looks for the
+//! first thing in it that decodes as a block, and decodes from there with
+//! markers in place of the bytes it can't see yet. Once the segment before is
+//! done, the markers are filled in from what it ended with. If a worker guessed
+//! wrong about where its block starts, its segment is decoded again from where
+//! the one before left off, so a bad guess costs time but never changes the
+//! output. The checksum of each member is checked as usual.
+//!
+//! A worker decodes about as fast as flate2 does, but filling in the markers
+//! and reading the input cost more on top. So on a single core this is about
+//! half as fast as flate2, and it only pays off with cores to spare. The
+//! `throughput` test measures it on a given machine.
+
+use memchr::memchr;
+
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::iter;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+const SEGMENT_SIZE: usize = 1048576; // 1MB of compressed input per job
+
+// How far past the end of its segment a worker can carry on decoding. Blocks
+// are much smaller than this in practice.
+const OVERLAP: usize = 262144; // 256KB
+
+const WINDOW_SIZE: usize = 32768;
+
+// Codes up to this long are decoded with a single table lookup
+const TABLE_BITS: u32 = 10;
+
+// The block header of a dynamic Huffman block that isn't the last one
+const DYNAMIC_BLOCK: u32 = 0b100;
+
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+/// Why a block couldn't be decoded.
+enum Stop {
+    /// The block goes on past the end of the input we have
+    NeedMore,
+    Invalid(&'static str),
+}
+
+/// Reads a deflate stream, which packs its bits from the least significant
+/// end of each byte.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    /// The next `n` bits, padded with zeros past the end of the input.
+    fn peek(&self, n: u32) -> u32 {
+        let byte = self.pos / 8;
+        let word = match self.data.get(byte..byte + 8) {
+            Some(word) => u64::from_le_bytes(word.try_into().unwrap()),
+            None => {
+                let mut word = [0; 8];
+                let rest = self.data.get(byte..).unwrap_or_default();
+                word[..rest.len()].copy_from_slice(rest);
+                u64::from_le_bytes(word)
+            }
+        };
+        ((word >> (self.pos % 8)) & ((1 << n) - 1)) as u32
+    }
+
+    fn consume(&mut self, n: u32) -> Result<(), Stop> {
+        if self.pos + n as usize > self.data.len() * 8 {
+            return Err(Stop::NeedMore);
+        }
+        self.pos += n as usize;
+        Ok(())
+    }
+
+    fn take(&mut self, n: u32) -> Result<u32, Stop> {
+        let value = self.peek(n);
+        self.consume(n)?;
+        Ok(value)
+    }
+}
+
+/// A canonical Huffman code, as described by the code length of each symbol.
+struct Huffman {
+    /// Indexed by the next `TABLE_BITS` bits, the symbol << 4 | its length,
+    /// or 0 for codes that are longer
+    table: Vec<u16>,
+    counts: [u16; 16],
+    /// Ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, Stop> {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Like zlib, allow an incomplete code only if it has a single symbol
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(Stop::Invalid("over-subscribed Huffman code"));
+            }
+        }
+        let codes: u16 = counts.iter().sum();
+        if left > 0 && codes > 1 {
+            return Err(Stop::Invalid("incomplete Huffman code"));
+        }
+
+        let mut next_code = [0u32; 16];
+        let mut offsets = [0usize; 16];
+        for length in 1..16 {
+            next_code[length] = (next_code[length - 1] + u32::from(counts[length - 1])) << 1;
+            offsets[length] = offsets[length - 1] + counts[length - 1] as usize;
+        }
+
+        let mut table = vec![0; 1 << TABLE_BITS];
+        let mut symbols = vec![0; codes as usize];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length == 0 {
+                continue;
+            }
+            let length = length as usize;
+            symbols[offsets[length]] = symbol as u16;
+            offsets[length] += 1;
+
+            let code = next_code[length];
+            next_code[length] += 1;
+            if length as u32 <= TABLE_BITS {
+                let reversed = code.reverse_bits() >> (32 - length);
+                let entry = (symbol as u16) << 4 | length as u16;
+                for index in (reversed as usize..1 << TABLE_BITS).step_by(1 << length) {
+                    table[index] = entry;
+                }
+            }
+        }
+
+        Ok(Huffman {
+            table,
+            counts,
+            symbols,
+        })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Stop> {
+        let entry = self.table[bits.peek(TABLE_BITS) as usize];
+        if entry != 0 {
+            bits.consume(u32::from(entry & 15))?;
+            return Ok(entry >> 4);
+        }
+
+        // Longer codes are rare enough to go a bit at a time
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as usize;
+            let count = count as usize;
+            if code < first + count {
+                return Ok(self.symbols[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Stop::Invalid("invalid Huffman code"))
+    }
+}
+
+fn fixed_codes() -> &'static (Huffman, Huffman) {
+    static FIXED: OnceLock<(Huffman, Huffman)> = OnceLock::new();
+    FIXED.get_or_init(|| {
+        let mut lengths = [8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        let literal_length = Huffman::new(&lengths).ok().unwrap();
+        let distance = Huffman::new(&[5; 32]).ok().unwrap();
+        (literal_length, distance)
+    })
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), Stop> {
+    let literal_lengths = bits.take(5)? as usize + 257;
+    let distances = bits.take(5)? as usize + 1;
+    let code_lengths = bits.take(4)? as usize + 4;
+    if literal_lengths > 286 || distances > 30 {
+        return Err(Stop::Invalid("too many length or distance symbols"));
+    }
+
+    let mut lengths = [0; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths)?;
+
+    let mut lengths = [0; 286 + 30];
+    let count = literal_lengths + distances;
+    let mut n = 0;
+    while n < count {
+        let (length, repeat) = match code_lengths.decode(bits)? {
+            length @ 0..=15 => (length as u8, 1),
+            16 if n > 0 => (lengths[n - 1], 3 + bits.take(2)? as usize),
+            16 => return Err(Stop::Invalid("repeated length with no first length")),
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if n + repeat > count {
+            return Err(Stop::Invalid("too many code lengths"));
+        }
+        lengths[n..n + repeat].fill(length);
+        n += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(Stop::Invalid("missing end of block code"));
+    }
+
+    let literal_length = Huffman::new(&lengths[..literal_lengths])?;
+    let distance = Huffman::new(&lengths[literal_lengths..count])?;
+    Ok((literal_length, distance))
+}
+
+/// Decode the block starting at `bits`, adding it to `out`. Values from 256 on
+/// stand for the bytes of the 32KB window before `out` started. Returns
+/// whether it was the last block.
+fn inflate_block(bits: &mut Bits, out: &mut Vec<u16>) -> Result<bool, Stop> {
+    let last = bits.take(1)? == 1;
+    match bits.take(2)? {
+        0 => {
+            bits.consume(((8 - bits.pos % 8) % 8) as u32)?;
+            let length = bits.take(16)?;
+            if length != !bits.take(16)? & 0xffff {
+                return Err(Stop::Invalid(
+                    "stored block length doesn't match its complement",
+                ));
+            }
+            let start = bits.pos / 8;
+            bits.consume(length * 8)?;
+            out.extend(bits.data[start..bits.pos / 8].iter().map(|&b| u16::from(b)));
+        }
+        1 => {
+            let (literal_length, distance) = fixed_codes();
+            inflate_codes(bits, out, literal_length, distance)?;
+        }
+        2 => {
+            let (literal_length, distance) = dynamic_codes(bits)?;
+            inflate_codes(bits, out, &literal_length, &distance)?;
+        }
+        _ => return Err(Stop::Invalid("invalid block type")),
+    }
+    Ok(last)
+}
+
+fn inflate_codes(
+    bits: &mut Bits,
+    out: &mut Vec<u16>,
+    literal_length: &Huffman,
+    distance: &Huffman,
+) -> Result<(), Stop> {
+    loop {
+        let symbol = literal_length.decode(bits)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u16);
+            continue;
+        } else if symbol == 256 {
+            return Ok(());
+        }
+
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(Stop::Invalid("invalid length symbol"));
+        }
+        let length = LENGTH_BASE[symbol] as usize + bits.take(LENGTH_EXTRA[symbol])? as usize;
+        let symbol = distance.decode(bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err(Stop::Invalid("invalid distance symbol"));
+        }
+        let distance = DISTANCE_BASE[symbol] as usize + bits.take(DISTANCE_EXTRA[symbol])? as usize;
+
+        let start = out.len();
+        if distance >= length && distance <= start {
+            out.extend_from_within(start - distance..start - distance + length);
+        } else if distance <= start {
+            // The copy overlaps what it's making, repeating the last few bytes
+            for i in start - distance..start - distance + length {
+                out.push(out[i]);
+            }
+        } else {
+            for i in start..start + length {
+                let byte = match i.checked_sub(distance) {
+                    Some(from) => out[from],
+                    None => (256 + WINDOW_SIZE + i - distance) as u16,
+                };
+                out.push(byte);
+            }
+        }
+    }
+}
+
+/// What a worker made of its segment. Bit positions are from the start of it.
+struct Inflated {
+    start: usize,
+    stop: usize,
+    out: Vec<u16>,
+    /// Whether it stopped at the end of the last block of a member
+    last: bool,
+}
+
+/// Find the first block that starts in the first `end` bits of `data`, and
+/// decode from there until the next block after `end` that a worker would
+/// start at, or the end of the member.
+fn inflate_segment(data: &[u8], end: usize) -> Option<Inflated> {
+    let mut out = Vec::with_capacity(data.len() * 8);
+    for start in 0..end {
+        let mut bits = Bits { data, pos: start };
+        if bits.peek(3) != DYNAMIC_BLOCK {
+            continue;
+        }
+        out.clear();
+        if inflate_block(&mut bits, &mut out).is_err() {
+            continue;
+        }
+
+        loop {
+            if bits.pos >= end && bits.peek(3) == DYNAMIC_BLOCK {
+                return Some(Inflated {
+                    start,
+                    stop: bits.pos,
+                    out,
+                    last: false,
+                });
+            }
+            match inflate_block(&mut bits, &mut out) {
+                Ok(false) => {}
+                Ok(true) => {
+                    return Some(Inflated {
+                        start,
+                        stop: bits.pos,
+                        out,
+                        last: true,
+                    })
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+    None
+}
+
+/// The length of the gzip member header at the start of `data`, if it's all
+/// there.
+fn header_length(data: &[u8]) -> io::Result<Option<usize>> {
+    let magic = [0x1f, 0x8b, 8];
+    let start = &data[..data.len().min(magic.len())];
+    if start != &magic[..start.len()] {
+        return Err(invalid_data("invalid gzip header"));
+    } else if data.len() < 10 {
+        return Ok(None);
+    }
+
+    let flags = data[3];
+    let mut length = 10;
+    if flags & FEXTRA != 0 {
+        let Some(extra) = data.get(length..length + 2) else {
+            return Ok(None);
+        };
+        length += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            match data.get(length..).and_then(|rest| memchr(0, rest)) {
+                Some(end) => length += end + 1,
+                None => return Ok(None),
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        length += 2;
+    }
+    Ok((length <= data.len()).then_some(length))
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The gzip member being decoded.
+struct Member {
+    crc: crc32fast::Hasher,
+    size: u32,
+}
+
+/// A segment of the compressed input, along with the bit more that a worker
+/// may need to finish its last block.
+struct Job {
+    segment: usize,
+    data: Vec<u8>,
+    /// Where the segment itself ends, in bits
+    end: usize,
+}
+
+pub struct ParallelGzDecoder<R> {
+    input: R,
+    segment_size: usize,
+    overlap: usize,
+    is_eof: bool,
+    /// The compressed input from `base` on
+    buf: Vec<u8>,
+    base: usize,
+    jobs: Sender<Job>,
+    results: Receiver<(usize, Option<Inflated>)>,
+    waiting: BTreeMap<usize, Option<Inflated>>,
+    in_flight: usize,
+    dispatched: usize,
+    /// The segment to be finished next
+    next: usize,
+    /// The bit in the input we've decoded up to
+    pos: usize,
+    member: Option<Member>,
+    /// Each byte value followed by the last 32KB of output, so that whatever a
+    /// decoded value stands for can be looked up
+    window: Vec<u8>,
+    out: Vec<u8>,
+    out_pos: usize,
+    is_done: bool,
+}
+
+impl<R: Read> ParallelGzDecoder<R> {
+    pub fn new(input: R, threads: usize) -> Self {
+        Self::with_segments(input, threads, SEGMENT_SIZE, OVERLAP)
+    }
+
+    /// Segments of the given size, and overlap past their end, in bytes.
+    fn with_segments(input: R, threads: usize, segment_size: usize, overlap: usize) -> Self {
+        let (jobs, jobs_rx) = channel::<Job>();
+        let (results, results_rx) = channel();
+
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        for _ in 0..threads {
+            let jobs_rx = Arc::clone(&jobs_rx);
+            let results = results.clone();
+            thread::spawn(move || loop {
+                let Ok(job) = jobs_rx.lock().unwrap().recv() else {
+                    break;
+                };
+                if results
+                    .send((job.segment, inflate_segment(&job.data, job.end)))
+                    .is_err()
+                {
+                    break;
+                }
+            });
+        }
+
+        ParallelGzDecoder {
+            input,
+            segment_size,
+            overlap,
+            is_eof: false,
+            buf: Vec::new(),
+            base: 0,
+            jobs,
+            results: results_rx,
+            waiting: BTreeMap::new(),
+            in_flight: threads * 2,
+            dispatched: 0,
+            next: 0,
+            pos: 0,
+            member: None,
+            window: (0..=255).chain(iter::repeat_n(0, WINDOW_SIZE)).collect(),
+            out: Vec::new(),
+            out_pos: 0,
+            is_done: false,
+        }
+    }
+
+    /// Read until the input we have goes up to `end`, or there's no more.
+    fn fill_to(&mut self, end: usize) -> io::Result<()> {
+        while !self.is_eof && self.base + self.buf.len() < end {
+            let wanted = (end - self.base - self.buf.len()) as u64;
+            if (&mut self.input).take(wanted).read_to_end(&mut self.buf)? == 0 {
+                self.is_eof = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_more(&mut self) -> io::Result<bool> {
+        let available = self.base + self.buf.len();
+        self.fill_to(available + self.segment_size)?;
+        Ok(self.base + self.buf.len() > available)
+    }
+
+    /// Hand out the segments coming up to the workers.
+    fn dispatch(&mut self) -> io::Result<()> {
+        while self.dispatched < self.next + self.in_flight {
+            let start = self.dispatched * self.segment_size;
+            self.fill_to(start + self.segment_size + self.overlap)?;
+            let available = self.base + self.buf.len();
+            if start >= available {
+                break;
+            }
+
+            let end = available.min(start + self.segment_size + self.overlap);
+            let data = self.buf[start - self.base..end - self.base].to_vec();
+            let job = Job {
+                segment: self.dispatched,
+                end: data.len().min(self.segment_size) * 8,
+                data,
+            };
+            if self.jobs.send(job).is_err() {
+                return Err(io::Error::other("gzip workers stopped"));
+            }
+            self.dispatched += 1;
+        }
+        Ok(())
+    }
+
+    /// Decode the next segment of the input into `out`. Returns false once
+    /// there's nothing left.
+    fn next_segment(&mut self) -> io::Result<bool> {
+        self.dispatch()?;
+        let start = self.next * self.segment_size;
+        if self.next >= self.dispatched {
+            if self.member.is_some() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(false);
+        }
+
+        let inflated = loop {
+            if let Some(inflated) = self.waiting.remove(&self.next) {
+                break inflated;
+            }
+            let Ok((segment, inflated)) = self.results.recv() else {
+                return Err(io::Error::other("gzip workers stopped"));
+            };
+            self.waiting.insert(segment, inflated);
+        };
+
+        // The last segment goes on to the end of the input
+        let is_last = self.is_eof && self.next + 1 == self.dispatched;
+        let end = if is_last {
+            usize::MAX
+        } else {
+            (start + self.segment_size) * 8
+        };
+
+        if self.member.is_none() {
+            self.start_member()?;
+        }
+        if let Some(inflated) = inflated {
+            if self.member.is_some() && start * 8 + inflated.start == self.pos {
+                self.emit(&inflated.out);
+                self.pos = start * 8 + inflated.stop;
+                if inflated.last {
+                    self.end_member()?;
+                }
+            }
+        }
+
+        // Decode whatever the worker didn't, or couldn't be trusted with
+        while self.pos < end {
+            if self.member.is_some() {
+                self.decode_block()?;
+            } else if !self.start_member()? {
+                break;
+            }
+        }
+
+        self.next += 1;
+        let keep = (self.pos / 8).min(self.dispatched * self.segment_size);
+        self.buf.drain(..keep - self.base);
+        self.base = keep;
+        Ok(true)
+    }
+
+    /// Read the header of the next member, if there is one.
+    fn start_member(&mut self) -> io::Result<bool> {
+        let start = self.pos / 8;
+        self.fill_to(start + 1)?;
+        if start >= self.base + self.buf.len() {
+            return Ok(false);
+        }
+
+        let length = loop {
+            if let Some(length) = header_length(&self.buf[start - self.base..])? {
+                break length;
+            }
+            if !self.read_more()? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        };
+        self.pos += length * 8;
+        self.member = Some(Member {
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+        });
+        Ok(true)
+    }
+
+    /// Check the trailer of the member against what we've decoded.
+    fn end_member(&mut self) -> io::Result<()> {
+        let start = self.pos.div_ceil(8);
+        self.fill_to(start + 8)?;
+        let Some(trailer) = self.buf.get(start - self.base..start - self.base + 8) else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+        let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+
+        let member = self.member.take().unwrap();
+        if crc != member.crc.finalize() || size != member.size {
+            return Err(invalid_data(
+                "corrupt gzip stream does not have a matching checksum",
+            ));
+        }
+        self.pos = (start + 8) * 8;
+        Ok(())
+    }
+
+    /// Decode a single block on this thread, knowing the window before it.
+    fn decode_block(&mut self) -> io::Result<()> {
+        let mut out = Vec::new();
+        loop {
+            let mut bits = Bits {
+                data: &self.buf,
+                pos: self.pos - self.base * 8,
+            };
+            match inflate_block(&mut bits, &mut out) {
+                Ok(last) => {
+                    self.pos = self.base * 8 + bits.pos;
+                    self.emit(&out);
+                    if last {
+                        self.end_member()?;
+                    }
+                    return Ok(());
+                }
+                Err(Stop::NeedMore) => {
+                    out.clear();
+                    if !self.read_more()? {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+                Err(Stop::Invalid(message)) => return Err(invalid_data(message)),
+            }
+        }
+    }
+
+    /// Fill in the window in decoded output and pass it on.
+    fn emit(&mut self, decoded: &[u16]) {
+        let start = self.out.len();
+        let window = &self.window;
+        self.out
+            .extend(decoded.iter().map(|&byte| window[byte as usize]));
+
+        let new = &self.out[start..];
+        let member = self.member.as_mut().unwrap();
+        member.crc.update(new);
+        member.size = member.size.wrapping_add(new.len() as u32);
+
+        let window = &mut self.window[256..];
+        if new.len() >= WINDOW_SIZE {
+            window.copy_from_slice(&new[new.len() - WINDOW_SIZE..]);
+        } else {
+            window.copy_within(new.len().., 0);
+            window[WINDOW_SIZE - new.len()..].copy_from_slice(new);
+        }
+    }
+}
+
+impl<R: Read> Read for ParallelGzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out_pos == self.out.len() && !self.is_done {
+            self.out.clear();
+            self.out_pos = 0;
+            self.is_done = !self.next_segment()?;
+        }
+
+        let n = buf.len().min(self.out.len() - self.out_pos);
+        buf[..n].copy_from_slice(&self.out[self.out_pos..self.out_pos + n]);
+        self.out_pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::bufread::MultiGzDecoder;
+    use flate2::write::GzEncoder;
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+
+    /// Made up data, half noise and half runs copied from up to 32KB back, so
+    /// that back-references cross every segment boundary.
+    fn sample(len: usize, mut seed: u64) -> Vec<u8> {
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut data = Vec::with_capacity(len + 64);
+        while data.len() < len {
+            let value = next();
+            if value % 2 == 0 && data.len() >= 64 {
+                let reach = data.len().min(WINDOW_SIZE) - 63;
+                let from = data.len() - 64 - (value >> 8) as usize % reach;
+                data.extend_from_within(from..from + 64);
+            } else {
+                data.extend((0..64).map(|_| next() as u8));
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    fn compress(data: &[u8], level: Compression) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(gz: &[u8], threads: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        ParallelGzDecoder::new(gz, threads).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// What flate2 makes of the same input, as the reference.
+    fn reference(gz: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        MultiGzDecoder::new(gz).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// The type of the first block of the first member.
+    fn first_block_type(gz: &[u8]) -> u8 {
+        let start = header_length(gz).unwrap().unwrap();
+        gz[start] >> 1 & 3
+    }
+
+    /// With segments and overlap this small, blocks run past the overlap and
+    /// many segments have no block starting in them at all.
+    fn decompress_small(gz: &[u8], threads: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        ParallelGzDecoder::with_segments(gz, threads, 4096, 1024).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn assert_round_trip(data: &[u8], gz: &[u8]) {
+        assert_eq!(reference(gz), data);
+        for threads in [1, 4] {
+            let out = decompress(gz, threads).unwrap();
+            assert!(out == data, "{threads} threads");
+        }
+    }
+
+    #[test]
+    fn stored_blocks() {
+        let data = sample(SEGMENT_SIZE + SEGMENT_SIZE / 2, 1);
+        let gz = compress(&data, Compression::none());
+        assert_eq!(first_block_type(&gz), 0);
+        assert_round_trip(&data, &gz);
+    }
+
+    #[test]
+    fn fixed_blocks() {
+        // Short enough that a Huffman code of its own isn't worth it
+        let data = b"fixed Huffman codes, fixed Huffman codes";
+        let gz = compress(data, Compression::default());
+        assert_eq!(first_block_type(&gz), 1);
+        assert_round_trip(data, &gz);
+    }
+
+    #[test]
+    fn dynamic_blocks_across_segments() {
+        let data = sample(5 * SEGMENT_SIZE, 2);
+        for level in [Compression::fast(), Compression::best()] {
+            let gz = compress(&data, level);
+            assert_eq!(first_block_type(&gz), 2);
+            // Otherwise there'd be nothing for the workers to split up
+            assert!(gz.len() > 2 * SEGMENT_SIZE);
+            assert_round_trip(&data, &gz);
+        }
+    }
+
+    #[test]
+    fn back_references_across_segments() {
+        let data = sample(5 * SEGMENT_SIZE, 10);
+        let gz = compress(&data, Compression::default());
+        assert!(gz.len() > 2 * SEGMENT_SIZE + OVERLAP);
+
+        // The worker for the second segment can't see the 32KB before it, and
+        // carries on into the overlap to finish its last block
+        let segment = &gz[SEGMENT_SIZE..2 * SEGMENT_SIZE + OVERLAP];
+        let inflated = inflate_segment(segment, SEGMENT_SIZE * 8).unwrap();
+        assert!(inflated.out.iter().any(|&value| value >= 256));
+        assert!(inflated.stop > SEGMENT_SIZE * 8);
+        assert!(!inflated.last);
+
+        assert_round_trip(&data, &gz);
+    }
+
+    #[test]
+    fn blocks_past_the_overlap() {
+        let data = sample(SEGMENT_SIZE, 11);
+        for level in [
+            Compression::none(),
+            Compression::fast(),
+            Compression::best(),
+        ] {
+            let gz = compress(&data, level);
+            for threads in [1, 4] {
+                let out = decompress_small(&gz, threads).unwrap();
+                assert!(out == data, "{threads} threads");
+            }
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_round_trip(b"", &compress(b"", Compression::default()));
+        assert_eq!(decompress(b"", 4).unwrap(), b"");
+    }
+
+    #[test]
+    fn header_fields() {
+        let data = sample(SEGMENT_SIZE, 3);
+        let mut encoder = GzBuilder::new()
+            .filename("releases.xml")
+            .comment("a comment")
+            .extra(vec![1, 2, 3])
+            .write(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        assert_round_trip(&data, &encoder.finish().unwrap());
+    }
+
+    #[test]
+    fn multiple_members() {
+        // Members ending both within a segment and across boundaries, and one
+        // made with each type of block
+        let parts = [
+            (sample(1000, 4), Compression::default()),
+            (sample(2 * SEGMENT_SIZE, 5), Compression::default()),
+            (sample(SEGMENT_SIZE / 2, 6), Compression::none()),
+            (b"fixed".to_vec(), Compression::default()),
+            (sample(SEGMENT_SIZE + 12345, 7), Compression::fast()),
+        ];
+        let mut data = Vec::new();
+        let mut gz = Vec::new();
+        for (part, level) in &parts {
+            data.extend_from_slice(part);
+            gz.extend(compress(part, *level));
+        }
+        assert_round_trip(&data, &gz);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = sample(2 * SEGMENT_SIZE, 8);
+        let mut gz = compress(&data, Compression::default());
+        let second = gz.len();
+        gz.extend(compress(b"second member", Compression::default()));
+
+        // In the header, the blocks and the trailer, of both members
+        let last = gz.len() - 1;
+        for len in [5, 100, SEGMENT_SIZE + 7, second - 6, second + 3, last] {
+            for threads in [1, 4] {
+                let err = decompress(&gz[..len], threads).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{len} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let data = sample(2 * SEGMENT_SIZE, 9);
+        let gz = compress(&data, Compression::default());
+
+        // The trailer is the CRC and then the size, each 4 bytes
+        for at in [gz.len() - 8, gz.len() - 4] {
+            let mut bad = gz.clone();
+            bad[at] ^= 1;
+            for threads in [1, 4] {
+                let err = decompress(&bad, threads).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_blocks() {
+        let data = sample(2 * SEGMENT_SIZE, 12);
+        let gz = compress(&data, Compression::default());
+
+        // Bad codes, lengths and distances are caught as they are decoded, and
+        // anything else by the checksum
+        let start = header_length(&gz).unwrap().unwrap();
+        let last = gz.len() - 20;
+        for at in [
+            start,
+            start + 1,
+            start + 100,
+            SEGMENT_SIZE - 3,
+            SEGMENT_SIZE + 5000,
+            last,
+        ] {
+            let mut bad = gz.clone();
+            bad[at] ^= 0x10;
+            for threads in [1, 4] {
+                let err = decompress(&bad, threads).unwrap_err();
+                let kind = err.kind();
+                assert!(
+                    kind == io::ErrorKind::InvalidData || kind == io::ErrorKind::UnexpectedEof,
+                    "byte {at}: {err}"
+                );
+            }
+        }
+    }
+
+    /// Throughput of flate2 against this decoder, on the file named by
+    /// `GZIP_BENCH_INPUT` or else on made up data:
+    ///
+    ///     cargo test --release throughput -- --ignored --nocapture
+    #[test]
+    #[ignore = "benchmark"]
+    fn throughput() {
+        use std::time::Instant;
+
+        let gz = match std::env::var_os("GZIP_BENCH_INPUT") {
+            Some(path) => std::fs::read(path).unwrap(),
+            None => compress(&sample(64 * SEGMENT_SIZE, 13), Compression::default()),
+        };
+        let megabytes = gz.len() as f64 / 1048576.0;
+        let report = |name: &str, run: &dyn Fn() -> usize| {
+            let started = Instant::now();
+            let len = run();
+            let seconds = started.elapsed().as_secs_f64();
+            println!(
+                "{name:<20} {:>8.1}MB/s in, {:>8.1}MB/s out",
+                megabytes / seconds,
+                len as f64 / 1048576.0 / seconds,
+            );
+        };
+
+        report("flate2", &|| reference(&gz).len());
+
+        // What one worker gets through, without the reading thread
+        report("worker", &|| {
+            let mut len = 0;
+            let mut from = header_length(&gz).unwrap().unwrap();
+            while from < gz.len() {
+                let to = gz.len().min(from + SEGMENT_SIZE + OVERLAP);
+                let end = (to - from).min(SEGMENT_SIZE) * 8;
+                if let Some(inflated) = inflate_segment(&gz[from..to], end) {
+                    len += inflated.out.len();
+                }
+                from += SEGMENT_SIZE;
+            }
+            len
+        });
+
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut counts = vec![1, 2, 4, cores];
+        counts.sort();
+        counts.dedup();
+        for threads in counts {
+            let name = format!("{threads} threads");
+            report(&name, &|| decompress(&gz, threads).unwrap().len());
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::BytesStart;
+use quick_xml::name::QName;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::array::{
+    LargeStringBuilder, ListBuilder, StringBuilder, StringDictionaryBuilder, StructBuilder,
+    UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Fields, Int8Type, Schema};
+use arrow::record_batch::RecordBatch;
+
+use crate::common::{data_quality_builder, parse_images, parse_text_list, struct_field};
+use crate::error::{integer, unescape_value, ProcessingError};
+use crate::output::OutputOptions;
+use crate::reader::{EventExt, EventReader};
+use crate::sink::{self, Sink};
+
+// Field positions within the label reference struct
+const LABEL_REF_ID: usize = 0;
+const LABEL_REF_NAME: usize = 1;
+
+/// Parent and sub labels refer to other labels by id and name.
+fn label_ref_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("id", DataType::UInt32, true),
+        Field::new("name", DataType::Utf8, false),
+    ])
+}
+
+fn label_ref_builder() -> StructBuilder {
+    StructBuilder::new(
+        label_ref_fields(),
+        vec![
+            Box::new(UInt32Builder::new()), // id
+            Box::new(StringBuilder::new()), // name
+        ],
+    )
+}
+
+struct LabelBatchWriter {
+    writer: Box<dyn Sink>,
+    pending: usize,
+    batch_size: usize,
+    id: UInt32Builder,
+    name: StringBuilder,
+    contactinfo: StringBuilder,
+    profile: LargeStringBuilder,
+    data_quality: StringDictionaryBuilder<Int8Type>,
+    urls: ListBuilder<StringBuilder>,
+    parent_label: StructBuilder,
+    sublabels: ListBuilder<StructBuilder>,
+    schema: Arc<Schema>,
+}
+
+impl LabelBatchWriter {
+    fn new(output_file_path: &str, options: &OutputOptions) -> Result<Self, ProcessingError> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::UInt32, false),
+            Field::new("name", DataType::Utf8, false),
+            Field::new("contactinfo", DataType::Utf8, true),
+            Field::new("profile", DataType::LargeUtf8, true),
+            Field::new(
+                "data_quality",
+                DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+                true,
+            ),
+            Field::new_list("urls", Field::new("item", DataType::Utf8, true), false),
+            Field::new_struct("parent_label", label_ref_fields(), true),
+            Field::new_list(
+                "sublabels",
+                Field::new_struct("item", label_ref_fields(), true),
+                false,
+            ),
+        ]));
+
+        let writer = sink::open(
+            Path::new(output_file_path),
+            "labels",
+            schema.clone(),
+            options,
+        )?;
+
+        Ok(LabelBatchWriter {
+            writer,
+            pending: 0,
+            batch_size: options.batch_size,
+            id: UInt32Builder::with_capacity(options.batch_size),
+            name: StringBuilder::with_capacity(options.batch_size, 64),
+            contactinfo: StringBuilder::new(),
+            profile: LargeStringBuilder::new(),
+            data_quality: data_quality_builder(),
+            urls: ListBuilder::new(StringBuilder::new()),
+            parent_label: label_ref_builder(),
+            sublabels: ListBuilder::new(label_ref_builder()),
+            schema,
+        })
+    }
+
+    fn push_id(&mut self, id: u32) {
+        self.id.append_value(id);
+    }
+
+    fn push_name(&mut self, name: &str) {
+        self.name.append_value(name);
+    }
+
+    fn push_contactinfo(&mut self, contactinfo: Option<&str>) {
+        self.contactinfo.append_option(contactinfo);
+    }
+
+    fn push_profile(&mut self, profile: Option<&str>) {
+        self.profile.append_option(profile);
+    }
+
+    fn push_data_quality(&mut self, data_quality: Option<&str>) {
+        match data_quality {
+            Some(data_quality) => self.data_quality.append_value(data_quality),
+            None => self.data_quality.append_null(),
+        }
+    }
+
+    fn push_parent_label_id(&mut self, id: Option<u32>) {
+        struct_field::<UInt32Builder>(&mut self.parent_label, LABEL_REF_ID).append_option(id);
+    }
+
+    fn push_parent_label_name(&mut self, name: &str) {
+        struct_field::<StringBuilder>(&mut self.parent_label, LABEL_REF_NAME).append_value(name);
+    }
+
+    fn push_end_of_parent_label(&mut self) {
+        self.parent_label.append(true);
+    }
+
+    fn push_parent_label_null(&mut self) {
+        // Children of a null struct still need a slot each
+        struct_field::<UInt32Builder>(&mut self.parent_label, LABEL_REF_ID).append_null();
+        struct_field::<StringBuilder>(&mut self.parent_label, LABEL_REF_NAME).append_value("");
+        self.parent_label.append(false);
+    }
+
+    fn write_label(&mut self) -> Result<(), ProcessingError> {
+        // Mark end of current label in list builders
+        self.urls.append(true);
+        self.sublabels.append(true);
+
+        self.pending += 1;
+
+        if self.pending == self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ProcessingError> {
+        if self.pending > 0 {
+            self.writer.write(&RecordBatch::try_new(
+                self.schema.clone(),
+                vec![
+                    Arc::new(self.id.finish()),
+                    Arc::new(self.name.finish()),
+                    Arc::new(self.contactinfo.finish()),
+                    Arc::new(self.profile.finish()),
+                    Arc::new(self.data_quality.finish()),
+                    Arc::new(self.urls.finish()),
+                    Arc::new(self.parent_label.finish()),
+                    Arc::new(self.sublabels.finish()),
+                ],
+            )?)?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<(), ProcessingError> {
+        self.flush()?;
+        self.writer.close()
+    }
+}
+
+pub fn write_labels(
+    reader: &mut EventReader,
+    output_file_path: &str,
+    options: &OutputOptions,
+) -> Result<(), ProcessingError> {
+    let mut writer = LabelBatchWriter::new(output_file_path, options)?;
+
+    reader.advance()?.expect_new_line()?;
+
+    let mut n: u128 = 0;
+
+    loop {
+        n += 1;
+        reader.set_record_id(None);
+        let event = reader.advance()?;
+
+        if event.is_end_of("labels") {
+            break;
+        }
+
+        event.expect_start_of("label")?;
+
+        parse_label(reader, &mut writer)?;
+
+        writer.write_label()?;
+
+        if n.is_multiple_of(10_000) {
+            println!("{n}");
+        }
+    }
+    writer.close()
+}
+
+fn parse_label(
+    reader: &mut EventReader,
+    writer: &mut LabelBatchWriter,
+) -> Result<(), ProcessingError> {
+    let mut has_id = false;
+    let mut has_name = false;
+    let mut has_contactinfo = false;
+    let mut has_profile = false;
+    let mut has_data_quality = false;
+    let mut has_parent_label = false;
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("label") {
+            break;
+        }
+
+        // Empty fields are filled in with nulls below
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        let event = event.expect_start()?;
+
+        // We can't assume the order of elements within a label
+        match event.name().into_inner() {
+            b"images" => parse_images(reader)?,
+            b"id" => {
+                let id = reader
+                    .read_text("id")?
+                    .ok_or(ProcessingError::expected_text())?;
+                let id = integer(id)?;
+                reader.set_record_id(Some(id));
+                writer.push_id(id);
+                has_id = true;
+            }
+            b"name" => {
+                let name = reader
+                    .read_text("name")?
+                    .ok_or(ProcessingError::expected_text())?;
+                writer.push_name(name);
+                has_name = true;
+            }
+            b"contactinfo" => {
+                let contactinfo = reader.read_text("contactinfo")?;
+                writer.push_contactinfo(contactinfo);
+                has_contactinfo = true;
+            }
+            b"profile" => {
+                let profile = reader.read_text("profile")?;
+                writer.push_profile(profile);
+                has_profile = true;
+            }
+            b"data_quality" => {
+                let data_quality = reader.read_text("data_quality")?;
+                writer.push_data_quality(data_quality);
+                has_data_quality = true;
+            }
+            b"urls" => parse_text_list(reader, "urls", "url", &mut writer.urls)?,
+            b"parentLabel" => {
+                let id = label_ref_id(&event)?;
+                writer.push_parent_label_id(id);
+
+                let name = reader
+                    .read_text("parentLabel")?
+                    .ok_or(ProcessingError::expected_text())?;
+                writer.push_parent_label_name(name);
+                writer.push_end_of_parent_label();
+                has_parent_label = true;
+            }
+            b"sublabels" => parse_sublabels(reader, &mut writer.sublabels)?,
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+
+    // Every label has an id and a name, so there's nothing to fill them in with
+    if !has_id {
+        return Err(ProcessingError::missing_field("id"));
+    }
+    if !has_name {
+        return Err(ProcessingError::missing_field("name"));
+    }
+    if !has_contactinfo {
+        writer.push_contactinfo(None);
+    }
+    if !has_profile {
+        writer.push_profile(None);
+    }
+    if !has_data_quality {
+        writer.push_data_quality(None);
+    }
+    if !has_parent_label {
+        writer.push_parent_label_null();
+    }
+
+    reader.advance()?.expect_new_line()?;
+
+    Ok(())
+}
+
+fn parse_sublabels(
+    reader: &mut EventReader,
+    sublabels: &mut ListBuilder<StructBuilder>,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("sublabels") {
+            break Ok(());
+        }
+
+        let event = event.expect_start_of("label")?;
+        let sublabel = sublabels.values();
+
+        let id = label_ref_id(&event)?;
+        struct_field::<UInt32Builder>(sublabel, LABEL_REF_ID).append_option(id);
+
+        let name = reader
+            .read_text("label")?
+            .ok_or(ProcessingError::expected_text())?;
+        struct_field::<StringBuilder>(sublabel, LABEL_REF_NAME).append_value(name);
+        sublabel.append(true);
+    }
+}
+
+fn label_ref_id(label_ref_start: &BytesStart) -> Result<Option<u32>, ProcessingError> {
+    let id = label_ref_start.attributes().find_map(|a| match a {
+        Ok(
+            attribute @ Attribute {
+                key: QName(b"id"), ..
+            },
+        ) => Some(attribute),
+        _ => None,
+    });
+    id.map(|id| integer(&unescape_value(&id)?)).transpose()
+}
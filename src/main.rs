@@ -1,469 +1,142 @@
-use flate2::bufread::GzDecoder;
-use quick_xml::events::{attributes::Attribute, BytesStart, BytesText, Event};
-use quick_xml::name::QName;
-use quick_xml::reader::Reader;
+mod artist;
+mod common;
+mod error;
+mod gzip;
+mod label;
+mod master;
+mod normalize;
+mod output;
+mod partition;
+mod pipeline;
+mod reader;
+mod release;
+mod sink;
 
 use std::env;
-use std::fs::File;
-use std::io::BufReader;
-use std::ops::Deref;
+use std::path::Path;
 use std::process;
-use std::sync::Arc;
+use std::thread;
 
-use parquet::arrow::ArrowWriter;
-use parquet::basic::Compression;
-use parquet::file::properties::WriterProperties;
-
-use arrow::array::{
-    BooleanBuilder, ListBuilder, StringArray, StringBuilder, StringDictionaryBuilder,
-    StructBuilder, UInt32Builder,
+use crate::error::{OnDuplicate, OnError, ProcessingError};
+use crate::output::{
+    parse_column_setting, parse_compression, parse_dictionary, parse_format, parse_layout,
+    parse_partition_by, parse_statistics, parse_writer_version, Format, Layout, OutputOptions,
 };
-use arrow::datatypes::{DataType, Field, Fields, Int8Type, Schema};
-use arrow::record_batch::RecordBatch;
-
-const READ_BUF_SIZE: usize = 1048576; // 1MB
-const BATCH_SIZE: usize = 10000;
+use crate::reader::{EventExt, EventReader};
 
-//TODO: Sort out unwraps -> actually throw specific errors
+//TODO: Sort out writer unwraps -> actually throw specific errors
 //TODO: Result type alias
 //TODO: Consider macros for common attribute wrangling
 //TODO: Tests, tests and more tests
 
-#[derive(Debug)]
-enum ProcessingError {
-    ExpectedStart,
-    ExpectedStartOf(&'static str),
-    ExpectedEndOf(&'static str),
-    ExpectedEmpty(&'static str),
-    ExpectedText,
-    ExpectedNewline,
-    ExpectedEof,
-    IoError(std::io::Error),
-    XMLParseError(quick_xml::Error),
-}
-
-impl From<std::io::Error> for ProcessingError {
-    fn from(err: std::io::Error) -> Self {
-        ProcessingError::IoError(err)
-    }
-}
-
-impl From<quick_xml::Error> for ProcessingError {
-    fn from(err: quick_xml::Error) -> Self {
-        ProcessingError::XMLParseError(err)
-    }
-}
-
-struct EventReader {
-    reader: Reader<BufReader<GzDecoder<BufReader<File>>>>,
-    buf: Vec<u8>,
-}
-
-impl EventReader {
-    fn new(file_path: String) -> Result<Self, ProcessingError> {
-        let input_file = File::open(file_path)?;
-        let input_file = BufReader::with_capacity(READ_BUF_SIZE, input_file);
-        let input_file = GzDecoder::new(input_file);
-        let input_file = BufReader::new(input_file);
-        let reader = Reader::from_reader(input_file);
-        let buf = Vec::new();
-        Ok(EventReader { reader, buf })
-    }
-
-    fn advance(&mut self) -> Result<Event, ProcessingError> {
-        self.buf.clear();
-        let event = self.reader.read_event_into(&mut self.buf)?;
-        Ok(event)
-    }
-}
-
-trait EventExt<'a> {
-    fn expect_start(self) -> Result<BytesStart<'a>, ProcessingError>;
-
-    fn expect_start_of(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError>;
-
-    fn expect_end_of(self, name: &'static str) -> Result<(), ProcessingError>;
-
-    fn expect_empty(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError>;
-
-    fn expect_new_line(self) -> Result<(), ProcessingError>;
-
-    fn expect_text(self) -> Result<BytesText<'a>, ProcessingError>;
-
-    fn expect_eof(self) -> Result<(), ProcessingError>;
-
-    fn is_end_of(&self, end_of: &'static str) -> bool;
-
-    fn is_empty_tag(&self) -> bool;
+/// The monthly Discogs data is split into one dump per kind of entity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DumpKind {
+    Releases,
+    Artists,
+    Labels,
+    Masters,
 }
 
-impl<'a> EventExt<'a> for Event<'a> {
-    fn expect_start_of(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError> {
-        match self {
-            Event::Start(e) if e.name().into_inner() == name.as_bytes() => Ok(e),
-            _ => Err(ProcessingError::ExpectedStartOf(name)),
-        }
-    }
-
-    fn expect_start(self) -> Result<BytesStart<'a>, ProcessingError> {
-        match self {
-            Event::Start(e) => Ok(e),
-            _ => Err(ProcessingError::ExpectedStart),
-        }
-    }
-
-    fn expect_end_of(self, name: &'static str) -> Result<(), ProcessingError> {
-        match self {
-            Event::End(e) if e.name().into_inner() == name.as_bytes() => Ok(()),
-            _ => Err(ProcessingError::ExpectedEndOf(name)),
+impl DumpKind {
+    fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"releases" => Some(DumpKind::Releases),
+            b"artists" => Some(DumpKind::Artists),
+            b"labels" => Some(DumpKind::Labels),
+            b"masters" => Some(DumpKind::Masters),
+            _ => None,
         }
     }
 
-    fn expect_empty(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError> {
+    /// Each dump is wrapped in a root element named after what it holds.
+    fn root(&self) -> &'static str {
         match self {
-            Event::Empty(e) if e.name().into_inner() == name.as_bytes() => Ok(e),
-            _ => Err(ProcessingError::ExpectedEmpty(name)),
-        }
-    }
-
-    fn expect_new_line(self) -> Result<(), ProcessingError> {
-        match self {
-            Event::Text(e) if e.deref() == b"\n" => Ok(()),
-            _ => Err(ProcessingError::ExpectedNewline),
-        }
-    }
-
-    fn expect_text(self) -> Result<BytesText<'a>, ProcessingError> {
-        match self {
-            Event::Text(e) => Ok(e),
-            _ => Err(ProcessingError::ExpectedText),
-        }
-    }
-
-    fn expect_eof(self) -> Result<(), ProcessingError> {
-        match self {
-            Event::Eof => Ok(()),
-            _ => Err(ProcessingError::ExpectedEof),
+            DumpKind::Releases => "releases",
+            DumpKind::Artists => "artists",
+            DumpKind::Labels => "labels",
+            DumpKind::Masters => "masters",
         }
     }
+}
 
-    fn is_end_of(&self, name: &'static str) -> bool {
-        matches!(&self, Event::End(e) if e.name().into_inner() == name.as_bytes())
-    }
+struct Args {
+    input_file_path: String,
+    output_file_path: String,
+    kind: Option<DumpKind>,
+    on_error: OnError,
+    on_duplicate: OnDuplicate,
+    threads: usize,
+    gzip_threads: usize,
+    output: OutputOptions,
+}
 
-    fn is_empty_tag(&self) -> bool {
-        matches!(&self, Event::Empty(_))
+fn main() {
+    let args = read_args(env::args());
+    if let Err(err) = run(&args) {
+        eprintln!("Error: {err}");
+        process::exit(1)
     }
 }
 
-//TODO: Figure out order, make consistent
-struct ReleaseBatchWriter {
-    writer: ArrowWriter<File>,
-    pending: usize,
-    //TODO: Lose plural where value in a single row is not a list
-    ids: UInt32Builder,
-    statuses: StringDictionaryBuilder<Int8Type>,
-    titles: StringBuilder,
-    artists: ListBuilder<StructBuilder>,
-    genres: ListBuilder<StringBuilder>,
-    styles: ListBuilder<StringBuilder>,
-    labels: ListBuilder<StructBuilder>,
-    is_main_release: BooleanBuilder,
-    master_id: UInt32Builder,
-    schema: Arc<Schema>,
+fn run(args: &Args) -> Result<(), ProcessingError> {
+    let mut reader = EventReader::new(args.input_file_path.clone(), args.gzip_threads)?;
+    // The reader stops where the error was found, so it knows where that was
+    convert(&mut reader, args).map_err(|err| err.locate(&reader))
 }
 
-impl ReleaseBatchWriter {
-    fn new(output_file_path: &str) -> Self {
-        //TODO: All ids should be probably be numeric
-        let label_fields = Fields::from(vec![
-            Field::new("id", DataType::Utf8, false),
-            Field::new("cat_no", DataType::Utf8, false),
-            Field::new("name", DataType::Utf8, false),
-        ]);
-
-        let artist_fields = Fields::from(vec![
-            Field::new("id", DataType::Utf8, false),
-            Field::new("name", DataType::Utf8, false),
-            Field::new("anv", DataType::Utf8, true),
-            Field::new("join", DataType::Utf8, true),
-        ]);
-
-        let schema = Arc::new(Schema::new(vec![
-            Field::new("id", DataType::UInt32, false),
-            //TODO: Is dictionary encoding actually useful/working?
-            Field::new(
-                "status",
-                DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
-                false,
-            ),
-            Field::new("title", DataType::Utf8, false),
-            Field::new_list(
-                "artists",
-                Field::new_struct("item", artist_fields.clone(), true),
-                false,
-            ),
-            //TODO: Should we dictionary encode genres and styles?
-            //TODO: Can we verify which encoding is written?
-            Field::new_list("genres", Field::new("item", DataType::Utf8, true), false),
-            Field::new_list("styles", Field::new("item", DataType::Utf8, true), false),
-            Field::new_list(
-                "labels",
-                Field::new_struct("item", label_fields.clone(), true),
-                false,
-            ),
-            Field::new("is_main_release", DataType::Boolean, true),
-            Field::new("master_id", DataType::UInt32, true),
-        ]));
+fn convert(reader: &mut EventReader, args: &Args) -> Result<(), ProcessingError> {
+    let root = reader.advance()?;
 
-        let writer_properties = WriterProperties::builder()
-            .set_compression(Compression::SNAPPY)
-            .build();
-
-        let output_file = File::create(output_file_path).unwrap();
-
-        let writer =
-            ArrowWriter::try_new(output_file, schema.clone(), Some(writer_properties)).unwrap();
-
-        let status_values =
-            StringArray::from(vec![Some("Accepted"), Some("Draft"), Some("Deleted")]);
-
-        //TODO: Review default capacities.
-
-        ReleaseBatchWriter {
-            writer,
-            pending: 0,
-            ids: UInt32Builder::with_capacity(BATCH_SIZE),
-            statuses: StringDictionaryBuilder::<Int8Type>::new_with_dictionary(3, &status_values)
-                .unwrap(),
-            titles: StringBuilder::with_capacity(BATCH_SIZE, 512),
-            artists: ListBuilder::new(StructBuilder::new(
-                artist_fields,
-                vec![
-                    //TODO: This seems a bit fragile?
-                    Box::new(StringBuilder::new()), // id
-                    Box::new(StringBuilder::new()), // name
-                    Box::new(StringBuilder::new()), // anv
-                    Box::new(StringBuilder::new()), // join
-                ],
-            )),
-            genres: ListBuilder::new(StringBuilder::new()),
-            styles: ListBuilder::new(StringBuilder::new()),
-            labels: ListBuilder::new(StructBuilder::new(
-                label_fields,
-                vec![
-                    //TODO: This seems a bit fragile?
-                    Box::new(StringBuilder::new()), // id
-                    Box::new(StringBuilder::new()), // cat no
-                    Box::new(StringBuilder::new()), // name
-                ],
-            )),
-            is_main_release: BooleanBuilder::new(),
-            master_id: UInt32Builder::new(),
-            schema,
+    let kind = match args.kind {
+        Some(kind) => {
+            root.expect_start_of(kind.root())?;
+            kind
         }
-    }
-
-    //TODO: For each of these "push" methods, we could record that this field,
-    // for the current record, has been populated. This will let us check for
-    // missing fields that would cause the columns to become un-aligned, but
-    // more importantly supply a null / default
-
-    fn push_id(&mut self, id: u32) {
-        self.ids.append_value(id);
-    }
-
-    fn push_status(&mut self, status: &str) {
-        self.statuses.append_value(status);
-    }
-    fn push_title(&mut self, title: &str) {
-        self.titles.append_value(title);
-    }
-
-    //TODO: Is there a way to define the order of struct in one place, safely.
-
-    fn push_artist_id(&mut self, id: &str) {
-        self.artists
-            .values()
-            .field_builder::<StringBuilder>(0)
-            .unwrap()
-            .append_value(id)
-    }
-
-    fn push_artist_name(&mut self, name: &str) {
-        self.artists
-            .values()
-            .field_builder::<StringBuilder>(1)
-            .unwrap()
-            .append_value(name)
-    }
-
-    fn push_artist_anv(&mut self, anv: &str) {
-        self.artists
-            .values()
-            .field_builder::<StringBuilder>(2)
-            .unwrap()
-            .append_value(anv)
-    }
-
-    fn push_artist_anv_null(&mut self) {
-        self.artists
-            .values()
-            .field_builder::<StringBuilder>(2)
-            .unwrap()
-            .append_null()
-    }
-
-    fn push_artist_join(&mut self, join: &str) {
-        self.artists
-            .values()
-            .field_builder::<StringBuilder>(3)
-            .unwrap()
-            .append_value(join)
-    }
-
-    fn push_artist_join_null(&mut self) {
-        self.artists
-            .values()
-            .field_builder::<StringBuilder>(3)
-            .unwrap()
-            .append_null()
-    }
-
-    fn push_end_of_artist(&mut self) {
-        self.artists.values().append(true)
-    }
-
-    fn push_genre(&mut self, genre: &str) {
-        self.genres.values().append_value(genre);
-    }
-
-    fn push_style(&mut self, style: &str) {
-        self.styles.values().append_value(style);
-    }
-
-    fn push_label_id(&mut self, id: &str) {
-        //TODO: Is there a nicer way than this?
-        self.labels
-            .values()
-            .field_builder::<StringBuilder>(0)
-            .unwrap()
-            .append_value(id);
-    }
-
-    fn push_label_cat_no(&mut self, cat_no: &str) {
-        //TODO: Is there a nicer way than this?
-        self.labels
-            .values()
-            .field_builder::<StringBuilder>(1)
-            .unwrap()
-            .append_value(cat_no);
-    }
-    fn push_label_name(&mut self, name: &str) {
-        //TODO: Is there a nicer way than this?
-        self.labels
-            .values()
-            .field_builder::<StringBuilder>(2)
-            .unwrap()
-            .append_value(name);
-    }
-
-    fn push_end_of_label(&mut self) {
-        self.labels.values().append(true);
-    }
-
-    fn push_is_main_release(&mut self, is_main: bool) {
-        self.is_main_release.append_value(is_main)
-    }
-
-    fn push_is_main_release_null(&mut self) {
-        self.is_main_release.append_null()
-    }
-
-    fn push_master_id(&mut self, id: u32) {
-        self.master_id.append_value(id);
-    }
+        None => {
+            let root = root.expect_start()?;
+            let name = root.name().into_inner();
+            DumpKind::from_name(name).ok_or_else(|| {
+                ProcessingError::UnknownDump(String::from_utf8_lossy(name).into_owned())
+            })?
+        }
+    };
 
-    fn push_master_id_null(&mut self) {
-        self.master_id.append_null();
+    let is_lenient = args.on_error != OnError::Fail || args.on_duplicate != OnDuplicate::Error;
+    if is_lenient && kind != DumpKind::Releases {
+        eprintln!("Skipping bad records and repeated elements is only supported for releases");
+        process::exit(1)
     }
-
-    fn write_release(&mut self) {
-        // Mark end of current release in list builders
-        self.artists.append(true);
-        self.genres.append(true);
-        self.styles.append(true);
-        self.labels.append(true);
-
-        self.pending += 1;
-
-        if self.pending == BATCH_SIZE {
-            self.flush()
-        }
+    let is_split = args.output.partition_by.is_some() || args.output.max_file_size.is_some();
+    if (args.output.layout != Layout::Nested || is_split) && kind != DumpKind::Releases {
+        eprintln!("The normalized layout and split output are only supported for releases");
+        process::exit(1)
     }
-
-    fn flush(&mut self) {
-        if self.pending > 0 {
-            self.writer
-                .write(
-                    &RecordBatch::try_new(
-                        self.schema.clone(),
-                        vec![
-                            Arc::new(self.ids.finish()),
-                            Arc::new(self.statuses.finish()),
-                            Arc::new(self.titles.finish()),
-                            Arc::new(self.artists.finish()),
-                            Arc::new(self.genres.finish()),
-                            Arc::new(self.styles.finish()),
-                            Arc::new(self.labels.finish()),
-                            Arc::new(self.is_main_release.finish()),
-                            Arc::new(self.master_id.finish()),
-                        ],
-                    )
-                    .unwrap(),
-                )
-                .unwrap();
-            self.pending = 0;
-        }
+    if is_split && args.output.format == Format::Sqlite {
+        eprintln!("SQLite output can't be split into files");
+        process::exit(1)
     }
-
-    fn close(mut self) {
-        self.flush();
-        self.writer.close().unwrap();
+    let is_duckdb = Path::new(&args.output_file_path).extension() == Some("duckdb".as_ref());
+    if is_duckdb && args.output.format == Format::Sqlite {
+        no_duckdb()
     }
-}
-
-fn main() -> Result<(), ProcessingError> {
-    let (input_file_path, output_file_path) = read_args(env::args());
-    let mut reader = EventReader::new(input_file_path)?;
-
-    let mut writer = ReleaseBatchWriter::new(&output_file_path);
-
-    reader.advance()?.expect_start_of("releases")?;
-    reader.advance()?.expect_new_line()?;
-
-    let mut n: u128 = 0;
 
-    loop {
-        n += 1;
-        let event = reader.advance()?;
-
-        if event.is_end_of("releases") {
-            break;
-        }
-
-        let release_start = event.expect_start_of("release")?;
-
-        parse_release_attributes(&release_start, &mut writer)?;
-        parse_release(&mut reader, &mut writer)?;
-
-        writer.write_release();
-
-        if n % 10_000 == 0 {
-            println!("{n}");
+    match kind {
+        DumpKind::Releases => {
+            let output_file_path = &args.output_file_path;
+            release::write_releases(
+                reader,
+                output_file_path,
+                args.on_error,
+                args.on_duplicate,
+                args.threads,
+                &args.output,
+            )
         }
-    }
-    writer.close();
+        DumpKind::Artists => artist::write_artists(reader, &args.output_file_path, &args.output),
+        DumpKind::Labels => label::write_labels(reader, &args.output_file_path, &args.output),
+        DumpKind::Masters => master::write_masters(reader, &args.output_file_path, &args.output),
+    }?;
 
     reader.advance()?.expect_new_line()?;
     reader.advance()?.expect_eof()?;
@@ -472,459 +145,172 @@ fn main() -> Result<(), ProcessingError> {
     Ok(())
 }
 
-fn parse_release_attributes(
-    release_start: &BytesStart,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    //TODO: deal with unwraps
-    for a in release_start.attributes() {
-        match a {
-            Ok(Attribute {
-                key: QName(b"id"),
-                value: id,
-            }) => {
-                let id = std::str::from_utf8(&id).unwrap().parse().unwrap();
-                writer.push_id(id);
+fn read_args(args: impl IntoIterator<Item = String>) -> Args {
+    let mut args = args.into_iter();
+    let exec = args.next();
+    let exec = exec.as_deref().unwrap_or("discogs-parquet");
+
+    let mut paths = Vec::new();
+    let mut kind = None;
+    let mut on_error = OnError::Fail;
+    let mut on_duplicate = OnDuplicate::Error;
+    let mut threads = thread::available_parallelism().map_or(1, usize::from);
+    // Decompressing on several threads takes more work overall, so is only
+    // worth it with cores to spare
+    let mut gzip_threads = 1;
+    let mut output = OutputOptions::default();
+
+    while let Some(arg) = args.next() {
+        // Flags can be given as either `--flag value` or `--flag=value`
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_owned())),
+            _ => (arg.as_str(), None),
+        };
+
+        match flag {
+            "--kind" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                kind = Some(DumpKind::from_name(value.as_bytes()).unwrap_or_else(|| usage(exec)));
             }
-            Ok(Attribute {
-                key: QName(b"status"),
-                value: status,
-            }) => {
-                let status = std::str::from_utf8(&status).unwrap();
-                writer.push_status(status);
+            "--on-error" => {
+                on_error = match value.or_else(|| args.next()).as_deref() {
+                    Some("fail") => OnError::Fail,
+                    Some("skip") => OnError::Skip,
+                    _ => usage(exec),
+                };
             }
-            _ => {
-                //TODO: This should be an error
-                dbg!(a);
-                panic!();
+            "--on-duplicate" => {
+                on_duplicate = match value.or_else(|| args.next()).as_deref() {
+                    Some("error") => OnDuplicate::Error,
+                    Some("first") => OnDuplicate::KeepFirst,
+                    Some("last") => OnDuplicate::KeepLast,
+                    _ => usage(exec),
+                };
             }
-        }
-    }
-    Ok(())
-}
-
-fn parse_release(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    let mut has_master_id = false;
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("release") {
-            break;
-        }
-
-        if event.is_empty_tag() {
-            continue;
-        }
-
-        let event = event.expect_start()?;
-
-        //TODO: Figure out a nice way to make sure we get each of these once
-
-        // We can't assume the order of elements within a release
-        match event.name().into_inner() {
-            b"title" => parse_title(reader, writer)?,
-            b"genres" => parse_genres(reader, writer)?,
-            b"styles" => parse_styles(reader, writer)?,
-            b"images" => parse_images(reader)?,
-            b"artists" => parse_artists(reader, writer)?,
-            b"extraartists" => parse_extra_artists(reader)?,
-            b"labels" => parse_labels(reader, writer)?,
-            b"formats" => parse_formats(reader)?,
-            b"country" => parse_country(reader)?,
-            b"data_quality" => parse_data_quality(reader)?,
-            b"tracklist" => parse_tracklist(reader)?,
-            b"videos" => parse_videos(reader)?,
-            b"released" => parse_released(reader)?,
-            b"companies" => parse_companies(reader)?,
-            b"notes" => parse_notes(reader)?,
-            b"identifiers" => parse_identifiers(reader)?,
-            b"master_id" => {
-                parse_master_id_attributes(&event, writer)?;
-                parse_master_id(reader, writer)?;
-                has_master_id = true;
+            "--threads" => {
+                threads = positive(value.or_else(|| args.next())).unwrap_or_else(|| usage(exec));
             }
-            _ => {
-                dbg!(&event);
-                dbg!(&event.name().into_inner());
-                //TODO: Error instead
-                panic!();
+            "--gzip-threads" => {
+                gzip_threads =
+                    positive(value.or_else(|| args.next())).unwrap_or_else(|| usage(exec));
             }
-        }
-    }
-    if !has_master_id {
-        writer.push_is_main_release_null();
-        writer.push_master_id_null();
-    }
-
-    reader.advance()?.expect_new_line()?;
-
-    Ok(())
-}
-
-fn parse_title(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    let title = reader.advance()?.expect_text()?;
-    let title = std::str::from_utf8(&title).unwrap();
-    writer.push_title(title);
-
-    reader.advance()?.expect_end_of("title")?;
-
-    Ok(())
-}
-
-fn parse_genres(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("genres") {
-            break Ok(());
-        }
-
-        event.expect_start_of("genre")?;
-
-        let genre = reader.advance()?.expect_text()?.into_inner();
-        let genre = std::str::from_utf8(&genre).unwrap();
-        //TODO: Can we do this without an alloc?
-        let genre = genre.replace("&amp;", "&");
-        writer.push_genre(&genre);
-
-        reader.advance()?.expect_end_of("genre")?;
-    }
-}
-
-fn parse_styles(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("styles") {
-            break Ok(());
-        }
-
-        event.expect_start_of("style")?;
-
-        let style = reader.advance()?.expect_text()?.into_inner();
-        let style = std::str::from_utf8(&style).unwrap();
-        //TODO: Can we do this without an alloc?
-        let style = style.replace("&amp;", "&");
-        writer.push_style(&style);
-
-        reader.advance()?.expect_end_of("style")?;
-    }
-}
-
-fn parse_labels(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("labels") {
-            break Ok(());
-        }
-
-        let label = event.expect_empty("label")?;
-
-        for a in label.attributes() {
-            match a {
-                Ok(Attribute {
-                    key: QName(b"id"),
-                    value: id,
-                }) => {
-                    let id = std::str::from_utf8(&id).unwrap();
-                    writer.push_label_id(id);
-                }
-                Ok(Attribute {
-                    key: QName(b"catno"),
-                    value: cat_no,
-                }) => {
-                    let cat_no = std::str::from_utf8(&cat_no).unwrap();
-                    writer.push_label_cat_no(cat_no);
+            "--format" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                if value == "duckdb" {
+                    no_duckdb()
                 }
-                Ok(Attribute {
-                    key: QName(b"name"),
-                    value: name,
-                }) => {
-                    let name = std::str::from_utf8(&name).unwrap();
-                    writer.push_label_name(name);
-                }
-                _ => {} //TODO: This should be an error
+                output.format = parse_format(&value).unwrap_or_else(|| usage(exec));
             }
-        }
-
-        writer.push_end_of_label();
-    }
-}
-
-fn parse_artists(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("artists") {
-            break Ok(());
-        }
-
-        event.expect_start_of("artist")?;
-
-        parse_artist(reader, writer)?;
-    }
-}
-
-fn parse_artist(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("artist") {
-            writer.push_end_of_artist();
-            break Ok(());
-        }
-
-        let event = event.expect_start()?;
-
-        match event.name().into_inner() {
-            b"id" => {
-                // Id should never be null
-                let event = reader.advance()?;
-                let id = event.expect_text()?;
-                let id = std::str::from_utf8(&id).unwrap();
-                writer.push_artist_id(id);
-                reader.advance()?.expect_end_of("id")?;
+            "--layout" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                output.layout = parse_layout(&value).unwrap_or_else(|| usage(exec));
             }
-            b"name" => {
-                // Name should never be null
-                let event = reader.advance()?;
-                let name = event.expect_text()?;
-                let name = std::str::from_utf8(&name).unwrap();
-                writer.push_artist_name(name);
-                reader.advance()?.expect_end_of("name")?;
+            "--partition-by" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                output.partition_by =
+                    Some(parse_partition_by(&value).unwrap_or_else(|| usage(exec)));
             }
-            b"anv" => {
-                // Artist name variation can be null
-                let event = reader.advance()?;
-                if event.is_end_of("anv") {
-                    writer.push_artist_anv_null();
-                } else {
-                    let anv = event.expect_text()?;
-                    let anv = std::str::from_utf8(&anv).unwrap();
-                    writer.push_artist_anv(anv);
-                    reader.advance()?.expect_end_of("anv")?;
-                }
+            "--max-file-size" => {
+                output.max_file_size =
+                    Some(positive(value.or_else(|| args.next())).unwrap_or_else(|| usage(exec)));
             }
-            b"join" => {
-                // Join field can be null
-                let event = reader.advance()?;
-                if event.is_end_of("join") {
-                    writer.push_artist_join_null();
-                } else {
-                    let join = event.expect_text()?;
-                    let join = std::str::from_utf8(&join).unwrap();
-                    writer.push_artist_join(join);
-                    reader.advance()?.expect_end_of("join")?;
-                }
+            "--list-separator" => {
+                output.list_separator =
+                    value.or_else(|| args.next()).unwrap_or_else(|| usage(exec));
             }
-            b"role" => {
-                // Tracks never seems to hold a value for main artist, so we can skip
-                reader.advance()?.expect_end_of("role")?;
+            "--batch-size" => {
+                output.batch_size =
+                    positive(value.or_else(|| args.next())).unwrap_or_else(|| usage(exec));
             }
-            b"tracks" => {
-                // Tracks never seems to hold a value for main artist, so we can skip
-                reader.advance()?.expect_end_of("tracks")?;
+            "--compression" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                output.compression = parse_compression(&value).unwrap_or_else(|| usage(exec));
             }
-            _ => {
-                dbg!(&event);
-                panic!("oh no"); //TODO: This should by an error
+            "--max-row-group-size" => {
+                output.max_row_group_size =
+                    Some(positive(value.or_else(|| args.next())).unwrap_or_else(|| usage(exec)));
             }
-        }
-    }
-}
-
-fn parse_images(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    // Images are ignored because uris are not in the dataset
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("images") {
-            break Ok(());
-        }
-
-        event.expect_empty("image")?;
-    }
-}
-
-fn parse_extra_artists(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse extra artists
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("extraartists") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_formats(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse formats
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("formats") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_country(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse country
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("country") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_data_quality(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse data quality
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("data_quality") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_master_id_attributes(
-    master_id_start: &BytesStart,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    //TODO: Parse master_id attributes
-
-    if let Some(Ok(Attribute {
-        key: QName(b"is_main_release"),
-        value: is_main,
-    })) = master_id_start.attributes().next()
-    {
-        let is_main = match is_main.as_ref() {
-            b"true" => true,
-            b"false" => false,
-            _ => panic!(),
-        };
-
-        writer.push_is_main_release(is_main);
-    } else {
-        //TODO: Real error
-        panic!()
-    }
-
-    Ok(())
-}
-
-fn parse_master_id(
-    reader: &mut EventReader,
-    writer: &mut ReleaseBatchWriter,
-) -> Result<(), ProcessingError> {
-    let master_id = reader.advance()?.expect_text()?;
-    let master_id = std::str::from_utf8(&master_id).unwrap().parse().unwrap();
-    writer.push_master_id(master_id);
-
-    reader.advance()?.expect_end_of("master_id")?;
-    Ok(())
-}
-
-fn parse_tracklist(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse tracklist
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("tracklist") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_videos(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse videos
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("videos") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_released(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse released
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("released") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_companies(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse companies
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("companies") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_notes(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse notes
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("notes") {
-            break Ok(());
-        }
-    }
-}
-
-fn parse_identifiers(reader: &mut EventReader) -> Result<(), ProcessingError> {
-    //TODO: Parse identifiers
-    loop {
-        let event = reader.advance()?;
-
-        if event.is_end_of("identifiers") {
-            break Ok(());
-        }
-    }
-}
-
-fn read_args(args: impl IntoIterator<Item = String>) -> (String, String) {
-    let mut args = args.into_iter();
-    let exec = args.next();
-    match (args.next(), args.next(), args.next()) {
-        (Some(input_path), Some(output_path), None) => (input_path, output_path),
-        _ => {
-            let exec = exec.as_deref().unwrap_or("discogs-parquet");
-            println!("Usage: {exec} input-file output-file");
-            process::exit(1)
-        }
-    }
+            "--data-page-size" => {
+                output.data_page_size =
+                    Some(positive(value.or_else(|| args.next())).unwrap_or_else(|| usage(exec)));
+            }
+            "--writer-version" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                output.writer_version = parse_writer_version(&value).unwrap_or_else(|| usage(exec));
+            }
+            // These can be given more than once, e.g. to turn something off
+            // for all columns and then back on for some
+            "--dictionary" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                let setting = parse_column_setting(&value, parse_dictionary);
+                output
+                    .dictionary
+                    .push(setting.unwrap_or_else(|| usage(exec)));
+            }
+            "--statistics" => {
+                let value = value.or_else(|| args.next()).unwrap_or_default();
+                let setting = parse_column_setting(&value, parse_statistics);
+                output
+                    .statistics
+                    .push(setting.unwrap_or_else(|| usage(exec)));
+            }
+            _ if flag.starts_with("--") => usage(exec),
+            _ => paths.push(arg),
+        }
+    }
+
+    let mut paths = paths.into_iter();
+    match (paths.next(), paths.next(), paths.next()) {
+        (Some(input_file_path), Some(output_file_path), None) => Args {
+            input_file_path,
+            output_file_path,
+            kind,
+            on_error,
+            on_duplicate,
+            threads,
+            gzip_threads,
+            output,
+        },
+        _ => usage(exec),
+    }
+}
+
+/// A count or size, which has to be more than zero.
+fn positive(value: Option<String>) -> Option<usize> {
+    value?.parse().ok().filter(|&n| n > 0)
+}
+
+/// There is no DuckDB sink yet, but DuckDB can read the SQLite output.
+fn no_duckdb() -> ! {
+    eprintln!(
+        "DuckDB output isn't supported yet. Write --format sqlite to a .sqlite file, \
+         and attach it in DuckDB with its sqlite extension"
+    );
+    process::exit(1)
+}
+
+fn usage(exec: &str) -> ! {
+    println!(
+        "Usage: {exec} [--kind releases|artists|labels|masters] [--on-error fail|skip] \
+         [--on-duplicate error|first|last] [--threads n] [--gzip-threads n] input-file|- output-file\n\
+         \n\
+         Output:\n  \
+           --format parquet|ipc|ipc-stream|ndjson|csv|sqlite\n  \
+           --layout nested|normalized\n  \
+           --partition-by year|country|id:n\n  \
+           --max-file-size bytes\n  \
+           --batch-size n\n\
+         \n\
+         CSV output:\n  \
+           --list-separator string\n\
+         \n\
+         Parquet output:\n  \
+           --compression none|snappy|lz4|zstd[:level]|gzip[:level]|brotli[:level]\n  \
+           --max-row-group-size n\n  \
+           --data-page-size bytes\n  \
+           --writer-version 1.0|2.0\n  \
+           --dictionary [column=]on|off\n  \
+           --statistics [column=]none|chunk|page"
+    );
+    process::exit(1)
 }
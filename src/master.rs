@@ -0,0 +1,298 @@
+use quick_xml::events::{attributes::Attribute, BytesStart};
+use quick_xml::name::QName;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::array::{
+    ListBuilder, StringBuilder, StringDictionaryBuilder, StructBuilder, UInt16Builder,
+    UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Int8Type, Schema};
+use arrow::record_batch::RecordBatch;
+
+use crate::common::{
+    artist_fields, artists_builder, data_quality_builder, parse_artists, parse_genres,
+    parse_images, parse_styles, parse_videos, video_fields, videos_builder, Repeated,
+};
+use crate::error::{integer, unescape_value, OnDuplicate, ProcessingError};
+use crate::output::OutputOptions;
+use crate::reader::{EventExt, EventReader};
+use crate::sink::{self, Sink};
+
+struct MasterBatchWriter {
+    writer: Box<dyn Sink>,
+    pending: usize,
+    batch_size: usize,
+    id: UInt32Builder,
+    main_release: UInt32Builder,
+    year: UInt16Builder,
+    title: StringBuilder,
+    artists: ListBuilder<StructBuilder>,
+    genres: ListBuilder<StringBuilder>,
+    styles: ListBuilder<StringBuilder>,
+    data_quality: StringDictionaryBuilder<Int8Type>,
+    videos: ListBuilder<StructBuilder>,
+    schema: Arc<Schema>,
+}
+
+impl MasterBatchWriter {
+    fn new(output_file_path: &str, options: &OutputOptions) -> Result<Self, ProcessingError> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::UInt32, false),
+            Field::new("main_release", DataType::UInt32, false),
+            Field::new("year", DataType::UInt16, true),
+            Field::new("title", DataType::Utf8, false),
+            Field::new_list(
+                "artists",
+                Field::new_struct("item", artist_fields(), true),
+                false,
+            ),
+            Field::new_list("genres", Field::new("item", DataType::Utf8, true), false),
+            Field::new_list("styles", Field::new("item", DataType::Utf8, true), false),
+            Field::new(
+                "data_quality",
+                DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+                true,
+            ),
+            Field::new_list(
+                "videos",
+                Field::new_struct("item", video_fields(), true),
+                false,
+            ),
+        ]));
+
+        let writer = sink::open(
+            Path::new(output_file_path),
+            "masters",
+            schema.clone(),
+            options,
+        )?;
+
+        Ok(MasterBatchWriter {
+            writer,
+            pending: 0,
+            batch_size: options.batch_size,
+            id: UInt32Builder::with_capacity(options.batch_size),
+            main_release: UInt32Builder::with_capacity(options.batch_size),
+            year: UInt16Builder::with_capacity(options.batch_size),
+            title: StringBuilder::with_capacity(options.batch_size, 512),
+            artists: artists_builder(),
+            genres: ListBuilder::new(StringBuilder::new()),
+            styles: ListBuilder::new(StringBuilder::new()),
+            data_quality: data_quality_builder(),
+            videos: videos_builder(),
+            schema,
+        })
+    }
+
+    fn push_id(&mut self, id: u32) {
+        self.id.append_value(id);
+    }
+
+    fn push_main_release(&mut self, main_release: u32) {
+        self.main_release.append_value(main_release);
+    }
+
+    fn push_year(&mut self, year: Option<u16>) {
+        self.year.append_option(year);
+    }
+
+    fn push_title(&mut self, title: &str) {
+        self.title.append_value(title);
+    }
+
+    fn push_data_quality(&mut self, data_quality: Option<&str>) {
+        match data_quality {
+            Some(data_quality) => self.data_quality.append_value(data_quality),
+            None => self.data_quality.append_null(),
+        }
+    }
+
+    fn write_master(&mut self) -> Result<(), ProcessingError> {
+        // Mark end of current master in list builders
+        self.artists.append(true);
+        self.genres.append(true);
+        self.styles.append(true);
+        self.videos.append(true);
+
+        self.pending += 1;
+
+        if self.pending == self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ProcessingError> {
+        if self.pending > 0 {
+            self.writer.write(&RecordBatch::try_new(
+                self.schema.clone(),
+                vec![
+                    Arc::new(self.id.finish()),
+                    Arc::new(self.main_release.finish()),
+                    Arc::new(self.year.finish()),
+                    Arc::new(self.title.finish()),
+                    Arc::new(self.artists.finish()),
+                    Arc::new(self.genres.finish()),
+                    Arc::new(self.styles.finish()),
+                    Arc::new(self.data_quality.finish()),
+                    Arc::new(self.videos.finish()),
+                ],
+            )?)?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<(), ProcessingError> {
+        self.flush()?;
+        self.writer.close()
+    }
+}
+
+pub fn write_masters(
+    reader: &mut EventReader,
+    output_file_path: &str,
+    options: &OutputOptions,
+) -> Result<(), ProcessingError> {
+    let mut writer = MasterBatchWriter::new(output_file_path, options)?;
+
+    reader.advance()?.expect_new_line()?;
+
+    let mut n: u128 = 0;
+
+    loop {
+        n += 1;
+        reader.set_record_id(None);
+        let event = reader.advance()?;
+
+        if event.is_end_of("masters") {
+            break;
+        }
+
+        let master_start = event.expect_start_of("master")?;
+
+        let master_id = parse_master_attributes(&master_start, &mut writer)?;
+        reader.set_record_id(Some(master_id));
+        parse_master(reader, &mut writer)?;
+
+        writer.write_master()?;
+
+        if n.is_multiple_of(10_000) {
+            println!("{n}");
+        }
+    }
+    writer.close()
+}
+
+/// Returns the id of the master, so that errors further in can refer to it.
+fn parse_master_attributes(
+    master_start: &BytesStart,
+    writer: &mut MasterBatchWriter,
+) -> Result<u32, ProcessingError> {
+    let mut master_id = None;
+    for a in master_start.attributes() {
+        match a {
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"id"), ..
+                },
+            ) => {
+                let id = integer(&unescape_value(&attribute)?)?;
+                writer.push_id(id);
+                master_id = Some(id);
+            }
+            Ok(Attribute { key, .. }) => {
+                return Err(ProcessingError::unknown_attribute(key.into_inner()))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    master_id.ok_or(ProcessingError::missing_field("id"))
+}
+
+fn parse_master(
+    reader: &mut EventReader,
+    writer: &mut MasterBatchWriter,
+) -> Result<(), ProcessingError> {
+    let mut has_main_release = false;
+    let mut has_year = false;
+    let mut has_title = false;
+    let mut has_data_quality = false;
+    // There's no policy for repeated elements in masters, so they're an error
+    let mut repeated = Repeated::new(OnDuplicate::Error);
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("master") {
+            break;
+        }
+
+        // Empty fields are filled in with nulls below
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        let event = event.expect_start()?;
+
+        // We can't assume the order of elements within a master
+        match event.name().into_inner() {
+            b"main_release" => {
+                let main_release = reader
+                    .read_text("main_release")?
+                    .ok_or(ProcessingError::expected_text())?;
+                writer.push_main_release(integer(main_release)?);
+                has_main_release = true;
+            }
+            b"images" => parse_images(reader)?,
+            b"artists" => parse_artists(reader, &mut writer.artists, &mut repeated)?,
+            b"genres" => parse_genres(reader, &mut writer.genres)?,
+            b"styles" => parse_styles(reader, &mut writer.styles)?,
+            b"year" => {
+                // A year of 0 means the year isn't known
+                let year = reader
+                    .read_text("year")?
+                    .map(integer)
+                    .transpose()?
+                    .filter(|year| *year != 0);
+                writer.push_year(year);
+                has_year = true;
+            }
+            b"title" => {
+                let title = reader
+                    .read_text("title")?
+                    .ok_or(ProcessingError::expected_text())?;
+                writer.push_title(title);
+                has_title = true;
+            }
+            b"data_quality" => {
+                let data_quality = reader.read_text("data_quality")?;
+                writer.push_data_quality(data_quality);
+                has_data_quality = true;
+            }
+            b"videos" => parse_videos(reader, &mut writer.videos, &mut repeated)?,
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+
+    // Every master has a main release and a title, so there's nothing to fill
+    // them in with
+    if !has_main_release {
+        return Err(ProcessingError::missing_field("main_release"));
+    }
+    if !has_title {
+        return Err(ProcessingError::missing_field("title"));
+    }
+    if !has_year {
+        writer.push_year(None);
+    }
+    if !has_data_quality {
+        writer.push_data_quality(None);
+    }
+
+    reader.advance()?.expect_new_line()?;
+
+    Ok(())
+}
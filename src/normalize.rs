@@ -0,0 +1,504 @@
+//! The normalized layout, for SQL engines that don't get on with lists of
+//! structs. Releases get a table of their own with just the single valued
+//! columns, and each list gets a child table with one row per item, keyed by
+//! the id of the release and the item's place in the list, counting from 1.
+//! The descriptions of each format are a list within a list, so they go one
+//! table further, keyed by the place of the format too.
+//!
+//! The child tables are made from the nested batches once they're built, so
+//! that parsing is the same whichever layout is written.
+
+use std::sync::Arc;
+
+use arrow::array::{
+    new_null_array, Array, ArrayRef, AsArray, BooleanArray, ListArray, StructArray, UInt32Array,
+    UInt32Builder,
+};
+use arrow::compute::{concat, take};
+use arrow::datatypes::{DataType, Field, FieldRef, Fields, Schema};
+use arrow::record_batch::RecordBatch;
+
+use crate::common::{artist_fields, extra_artist_fields, video_fields};
+use crate::error::ProcessingError;
+use crate::release::{
+    company_fields, format_fields, identifier_fields, label_fields, release_schema,
+    sub_track_fields,
+};
+
+/// What each table is made from.
+enum Source {
+    /// The single valued columns of the releases
+    Releases,
+    /// Artists and extra artists, of the release and of each track
+    Artists,
+    /// Tracks and their sub tracks
+    Tracks,
+    /// A list of strings, and the name of the column for them
+    Strings(&'static str, &'static str),
+    /// A list of structs, with a column for each field that isn't a list
+    Structs(&'static str),
+    /// The descriptions of each format, keyed by its ordinal as well
+    FormatDescriptions,
+}
+
+const TABLES: [(&str, Source); 11] = [
+    ("releases", Source::Releases),
+    ("release_artists", Source::Artists),
+    ("release_labels", Source::Structs("labels")),
+    ("release_genres", Source::Strings("genres", "genre")),
+    ("release_styles", Source::Strings("styles", "style")),
+    ("release_tracks", Source::Tracks),
+    ("release_formats", Source::Structs("formats")),
+    ("release_format_descriptions", Source::FormatDescriptions),
+    ("release_identifiers", Source::Structs("identifiers")),
+    ("release_companies", Source::Structs("companies")),
+    ("release_videos", Source::Structs("videos")),
+];
+
+/// The names and schemas of the tables, in the order `normalize` returns
+/// their batches.
+pub fn tables() -> Vec<(&'static str, Arc<Schema>)> {
+    TABLES
+        .iter()
+        .map(|(name, source)| (*name, Arc::new(schema(source))))
+        .collect()
+}
+
+/// The columns of a table that hold ids, of its own records or of those they
+/// refer to, which are the ones worth indexing in a database.
+pub fn keys(schema: &Schema) -> Vec<&str> {
+    (schema.fields().iter())
+        .map(|field| field.name().as_str())
+        .filter(|name| *name == "id" || name.ends_with("_id"))
+        .collect()
+}
+
+fn schema(source: &Source) -> Schema {
+    let release_id = Field::new("release_id", DataType::UInt32, false);
+    let ordinal = Field::new("ordinal", DataType::UInt32, false);
+
+    match source {
+        Source::Releases => {
+            let nested = release_schema();
+            let fields = nested.fields().iter().filter(|f| !is_list(f)).cloned();
+            Schema::new(fields.collect::<Fields>())
+        }
+        Source::Artists => {
+            // Artists and extra artists share the columns they have in common
+            let mut fields = vec![
+                release_id,
+                Field::new("track_ordinal", DataType::UInt32, true),
+                Field::new("sub_track_ordinal", DataType::UInt32, true),
+                Field::new("is_extra", DataType::Boolean, false),
+                ordinal,
+            ];
+            for field in artist_fields().iter().chain(extra_artist_fields().iter()) {
+                if !fields.iter().any(|f| f.name() == field.name()) {
+                    fields.push(field.as_ref().clone());
+                }
+            }
+            Schema::new(fields)
+        }
+        Source::Tracks => {
+            // Sub tracks follow their track, with its ordinal and their own
+            let mut fields = vec![
+                release_id,
+                ordinal,
+                Field::new("sub_track_ordinal", DataType::UInt32, true),
+            ];
+            fields.extend(sub_track_fields().into_iter().filter(|f| !is_list(f)));
+            Schema::new(fields)
+        }
+        Source::Strings(_, item) => Schema::new(vec![
+            release_id,
+            ordinal,
+            Field::new(*item, DataType::Utf8, true),
+        ]),
+        Source::Structs(list) => {
+            let item_fields = match *list {
+                "labels" => label_fields(),
+                "formats" => format_fields(),
+                "identifiers" => identifier_fields(),
+                "companies" => company_fields(),
+                "videos" => video_fields(),
+                _ => unreachable!("no child table for {list}"),
+            };
+            let mut fields = vec![release_id, ordinal];
+            fields.extend(
+                (item_fields.iter())
+                    .filter(|f| !is_list(f))
+                    .map(|f| f.as_ref().clone()),
+            );
+            Schema::new(fields)
+        }
+        Source::FormatDescriptions => Schema::new(vec![
+            release_id,
+            Field::new("format_ordinal", DataType::UInt32, false),
+            ordinal,
+            Field::new("description", DataType::Utf8, true),
+        ]),
+    }
+}
+
+fn is_list(field: &Field) -> bool {
+    matches!(field.data_type(), DataType::List(_))
+}
+
+/// Split a batch of nested releases into a batch for each table.
+pub fn normalize(batch: &RecordBatch) -> Result<Vec<RecordBatch>, ProcessingError> {
+    let release_ids = column(batch, "id");
+
+    TABLES
+        .iter()
+        .map(|(_, source)| {
+            let schema = Arc::new(schema(source));
+            let columns = match source {
+                Source::Releases => (schema.fields().iter())
+                    .map(|f| column(batch, f.name()))
+                    .collect(),
+                Source::Artists => artists(batch, &schema)?,
+                Source::Tracks => tracks(batch, &schema)?,
+                Source::Strings(list, _) => {
+                    let items = explode(column(batch, list).as_list());
+                    vec![
+                        take(&release_ids, &items.rows, None)?,
+                        Arc::new(items.ordinals),
+                        items.values,
+                    ]
+                }
+                Source::Structs(list) => {
+                    let items = explode(column(batch, list).as_list());
+                    let mut columns: Vec<ArrayRef> = vec![
+                        take(&release_ids, &items.rows, None)?,
+                        Arc::new(items.ordinals),
+                    ];
+                    columns.extend(fields_of(items.values.as_struct(), &schema.fields()[2..]));
+                    columns
+                }
+                Source::FormatDescriptions => format_descriptions(batch)?,
+            };
+            Ok(RecordBatch::try_new(schema, columns)?)
+        })
+        .collect()
+}
+
+fn column(batch: &RecordBatch, name: &str) -> ArrayRef {
+    let column = batch.column_by_name(name);
+    column
+        .expect("releases are batched with every column of the release schema")
+        .clone()
+}
+
+/// The items of a list column, with the row each came from and its place in
+/// that row's list.
+struct Items {
+    rows: UInt32Array,
+    ordinals: UInt32Array,
+    values: ArrayRef,
+}
+
+fn explode(list: &ListArray) -> Items {
+    let offsets = list.value_offsets();
+    let mut rows = UInt32Builder::new();
+    let mut ordinals = UInt32Builder::new();
+
+    for (row, range) in offsets.windows(2).enumerate() {
+        for ordinal in 1..=range[1] - range[0] {
+            rows.append_value(row as u32);
+            ordinals.append_value(ordinal as u32);
+        }
+    }
+
+    let start = offsets[0] as usize;
+    let end = offsets[list.len()] as usize;
+    Items {
+        rows: rows.finish(),
+        ordinals: ordinals.finish(),
+        values: list.values().slice(start, end - start),
+    }
+}
+
+/// The columns of `items` named in `fields`, or nulls for those it hasn't got.
+fn fields_of(items: &StructArray, fields: &[FieldRef]) -> Vec<ArrayRef> {
+    fields
+        .iter()
+        .map(|field| match items.column_by_name(field.name()) {
+            Some(column) => column.clone(),
+            None => new_null_array(field.data_type(), items.len()),
+        })
+        .collect()
+}
+
+fn artists(batch: &RecordBatch, schema: &Schema) -> Result<Vec<ArrayRef>, ProcessingError> {
+    let release_ids = column(batch, "id");
+    let nulls = new_null_array(&DataType::UInt32, batch.num_rows());
+    let mut parts = Vec::new();
+
+    // Each artist is keyed by the release, track and sub track it belongs to
+    let keys = [release_ids, nulls.clone(), nulls];
+    parts.push(artist_rows(
+        &keys,
+        false,
+        &column(batch, "artists"),
+        schema,
+    )?);
+    parts.push(artist_rows(
+        &keys,
+        true,
+        &column(batch, "extra_artists"),
+        schema,
+    )?);
+
+    let tracks = explode(column(batch, "tracklist").as_list());
+    let track = tracks.values.as_struct();
+    let track_keys = [
+        take(&keys[0], &tracks.rows, None)?,
+        Arc::new(tracks.ordinals) as ArrayRef,
+        new_null_array(&DataType::UInt32, track.len()),
+    ];
+    parts.push(artist_rows(
+        &track_keys,
+        false,
+        &field(track, "artists"),
+        schema,
+    )?);
+    parts.push(artist_rows(
+        &track_keys,
+        true,
+        &field(track, "extra_artists"),
+        schema,
+    )?);
+
+    let sub_tracks = explode(field(track, "sub_tracks").as_list());
+    let sub_track = sub_tracks.values.as_struct();
+    let sub_track_keys = [
+        take(&track_keys[0], &sub_tracks.rows, None)?,
+        take(&track_keys[1], &sub_tracks.rows, None)?,
+        Arc::new(sub_tracks.ordinals) as ArrayRef,
+    ];
+    parts.push(artist_rows(
+        &sub_track_keys,
+        false,
+        &field(sub_track, "artists"),
+        schema,
+    )?);
+    parts.push(artist_rows(
+        &sub_track_keys,
+        true,
+        &field(sub_track, "extra_artists"),
+        schema,
+    )?);
+
+    concat_columns(&parts)
+}
+
+/// One row per artist in `artists`, with the keys of the row it came from.
+fn artist_rows(
+    keys: &[ArrayRef; 3],
+    is_extra: bool,
+    artists: &ArrayRef,
+    schema: &Schema,
+) -> Result<Vec<ArrayRef>, ProcessingError> {
+    let items = explode(artists.as_list());
+    let artist = items.values.as_struct();
+
+    let mut columns = (keys.iter())
+        .map(|key| take(key, &items.rows, None))
+        .collect::<Result<Vec<_>, _>>()?;
+    columns.push(Arc::new(BooleanArray::from(vec![is_extra; artist.len()])));
+    columns.push(Arc::new(items.ordinals));
+    columns.extend(fields_of(artist, &schema.fields()[5..]));
+    Ok(columns)
+}
+
+fn tracks(batch: &RecordBatch, schema: &Schema) -> Result<Vec<ArrayRef>, ProcessingError> {
+    let release_ids = column(batch, "id");
+
+    let tracks = explode(column(batch, "tracklist").as_list());
+    let track = tracks.values.as_struct();
+    let track_release_ids = take(&release_ids, &tracks.rows, None)?;
+    let track_ordinals: ArrayRef = Arc::new(tracks.ordinals);
+
+    let mut track_rows = vec![
+        track_release_ids.clone(),
+        track_ordinals.clone(),
+        new_null_array(&DataType::UInt32, track.len()),
+    ];
+    track_rows.extend(fields_of(track, &schema.fields()[3..]));
+
+    let sub_tracks = explode(field(track, "sub_tracks").as_list());
+    let sub_track = sub_tracks.values.as_struct();
+    let mut sub_track_rows = vec![
+        take(&track_release_ids, &sub_tracks.rows, None)?,
+        take(&track_ordinals, &sub_tracks.rows, None)?,
+        Arc::new(sub_tracks.ordinals),
+    ];
+    sub_track_rows.extend(fields_of(sub_track, &schema.fields()[3..]));
+
+    concat_columns(&[track_rows, sub_track_rows])
+}
+
+fn format_descriptions(batch: &RecordBatch) -> Result<Vec<ArrayRef>, ProcessingError> {
+    let release_ids = column(batch, "id");
+
+    let formats = explode(column(batch, "formats").as_list());
+    let format_release_ids = take(&release_ids, &formats.rows, None)?;
+    let format_ordinals: ArrayRef = Arc::new(formats.ordinals);
+
+    let descriptions = explode(field(formats.values.as_struct(), "descriptions").as_list());
+    Ok(vec![
+        take(&format_release_ids, &descriptions.rows, None)?,
+        take(&format_ordinals, &descriptions.rows, None)?,
+        Arc::new(descriptions.ordinals),
+        descriptions.values,
+    ])
+}
+
+fn field(items: &StructArray, name: &str) -> ArrayRef {
+    let field = items.column_by_name(name);
+    field
+        .expect("nested structs are built with every field of their type")
+        .clone()
+}
+
+/// Put the rows of each part one after the other.
+fn concat_columns(parts: &[Vec<ArrayRef>]) -> Result<Vec<ArrayRef>, ProcessingError> {
+    (0..parts[0].len())
+        .map(|i| {
+            let arrays: Vec<&dyn Array> = parts.iter().map(|part| part[i].as_ref()).collect();
+            Ok(concat(&arrays)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow::datatypes::UInt32Type;
+
+    use crate::release::parse_batch;
+
+    const RELEASE: &str = concat!(
+        r#"<release id="10" status="Accepted"><title>Nested</title>"#,
+        r#"<artists><artist><id>1</id><name>A</name></artist><artist><id>2</id><name>B</name></artist></artists>"#,
+        r#"<extraartists><artist><id>3</id><name>C</name><role>Producer</role></artist></extraartists>"#,
+        r#"<genres><genre>Rock</genre><genre>Jazz</genre></genres>"#,
+        r#"<formats><format name="Vinyl" qty="1" text=""><descriptions><description>LP</description><description>Album</description></descriptions></format><format name="CD" qty="1" text=""/></formats>"#,
+        r#"<tracklist>"#,
+        r#"<track><position>1</position><title>One</title><artists><artist><id>4</id><name>D</name></artist></artists></track>"#,
+        r#"<track><position>2</position><title>Two</title>"#,
+        r#"<extraartists><artist><id>5</id><name>E</name><role>Mixed By</role></artist></extraartists>"#,
+        r#"<sub_tracks>"#,
+        r#"<track><position>2a</position><title>Two A</title><extraartists><artist><id>6</id><name>F</name></artist></extraartists></track>"#,
+        r#"<track><position>2b</position><title>Two B</title></track>"#,
+        r#"</sub_tracks></track>"#,
+        r#"</tracklist></release>"#,
+    );
+    // Nothing in any list but one genre, whose ordinal starts again from 1
+    const EMPTY: &str = r#"<release id="20" status="Accepted"><title>Empty</title><genres><genre>Pop</genre></genres><tracklist></tracklist></release>"#;
+
+    fn table(name: &str) -> RecordBatch {
+        let batch = parse_batch(&[RELEASE, EMPTY]);
+        let tables = tables().into_iter().zip(normalize(&batch).unwrap());
+        let (_, table) = tables.into_iter().find(|((n, _), _)| *n == name).unwrap();
+        table
+    }
+
+    fn ids(table: &RecordBatch, name: &str) -> Vec<Option<u32>> {
+        let column = table.column_by_name(name).unwrap();
+        column.as_primitive::<UInt32Type>().iter().collect()
+    }
+
+    #[test]
+    fn releases() {
+        let releases = table("releases");
+        assert_eq!(ids(&releases, "id"), [Some(10), Some(20)]);
+        assert!(releases.schema().fields().iter().all(|f| !is_list(f)));
+    }
+
+    #[test]
+    fn strings() {
+        let genres = table("release_genres");
+        assert_eq!(ids(&genres, "release_id"), [Some(10), Some(10), Some(20)]);
+        assert_eq!(ids(&genres, "ordinal"), [Some(1), Some(2), Some(1)]);
+        let genre = genres.column_by_name("genre").unwrap().as_string::<i32>();
+        assert_eq!(
+            genre.iter().collect::<Vec<_>>(),
+            [Some("Rock"), Some("Jazz"), Some("Pop")]
+        );
+    }
+
+    #[test]
+    fn tracks_and_sub_tracks() {
+        let tracks = table("release_tracks");
+        assert_eq!(tracks.num_rows(), 4);
+        assert_eq!(ids(&tracks, "release_id"), [Some(10); 4]);
+        // Sub tracks come after the tracks, keyed by the track they're on
+        assert_eq!(
+            ids(&tracks, "ordinal"),
+            [Some(1), Some(2), Some(2), Some(2)]
+        );
+        assert_eq!(
+            ids(&tracks, "sub_track_ordinal"),
+            [None, None, Some(1), Some(2)]
+        );
+        let position = tracks
+            .column_by_name("position")
+            .unwrap()
+            .as_string::<i32>();
+        let position: Vec<_> = position.iter().collect();
+        assert_eq!(position, [Some("1"), Some("2"), Some("2a"), Some("2b")]);
+    }
+
+    #[test]
+    fn artists_of_releases_tracks_and_sub_tracks() {
+        let artists = table("release_artists");
+        assert_eq!(artists.num_rows(), 6);
+        assert_eq!(ids(&artists, "id"), [1, 2, 3, 4, 5, 6].map(Some));
+        assert_eq!(ids(&artists, "release_id"), [Some(10); 6]);
+        assert_eq!(
+            ids(&artists, "track_ordinal"),
+            [None, None, None, Some(1), Some(2), Some(2)]
+        );
+        assert_eq!(
+            ids(&artists, "sub_track_ordinal"),
+            [None, None, None, None, None, Some(1)]
+        );
+        assert_eq!(ids(&artists, "ordinal"), [1, 2, 1, 1, 1, 1].map(Some));
+        let is_extra = artists.column_by_name("is_extra").unwrap().as_boolean();
+        let is_extra: Vec<_> = is_extra.iter().map(Option::unwrap).collect();
+        assert_eq!(is_extra, [false, false, true, false, true, true]);
+    }
+
+    #[test]
+    fn formats_and_their_descriptions() {
+        let formats = table("release_formats");
+        assert_eq!(ids(&formats, "release_id"), [Some(10), Some(10)]);
+        assert_eq!(ids(&formats, "ordinal"), [Some(1), Some(2)]);
+        assert!(formats.column_by_name("descriptions").is_none());
+
+        // The second format has no descriptions, so no rows
+        let descriptions = table("release_format_descriptions");
+        assert_eq!(ids(&descriptions, "release_id"), [Some(10), Some(10)]);
+        assert_eq!(ids(&descriptions, "format_ordinal"), [Some(1), Some(1)]);
+        assert_eq!(ids(&descriptions, "ordinal"), [Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn empty_lists() {
+        for name in [
+            "release_labels",
+            "release_identifiers",
+            "release_companies",
+            "release_videos",
+        ] {
+            let table = table(name);
+            assert_eq!(table.num_rows(), 0, "{name}");
+            assert_eq!(
+                table.schema(),
+                tables().iter().find(|(n, _)| *n == name).unwrap().1
+            );
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! How the output is written. The defaults suit most readers, but query
+//! engines differ in which formats, codecs, page sizes and statistics they do
+//! best with, so all of it can be set on the command line.
+
+use parquet::basic::{BrotliLevel, Compression, GzipLevel, ZstdLevel};
+use parquet::file::properties::{EnabledStatistics, WriterProperties, WriterVersion};
+use parquet::format::KeyValue;
+use parquet::schema::types::ColumnPath;
+
+const DEFAULT_BATCH_SIZE: usize = 10000;
+
+/// Written to the footer of each file under `discogs_schema_version`, so that
+/// readers can tell which layout they have. Files without it are version 1,
+/// where ids of nested artists and labels were strings.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// How releases are laid out across tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// One table, with lists of structs for artists, tracks and so on
+    Nested,
+    /// A directory with a table of releases and a child table for each list,
+    /// see `normalize`
+    Normalized,
+}
+
+/// What the records are written as, see `sink`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Parquet,
+    /// The Arrow IPC file format, also known as Feather
+    Ipc,
+    /// The Arrow IPC stream format
+    IpcStream,
+    /// Newline delimited JSON, one object per record
+    Json,
+    /// CSV, with lists joined into one value
+    Csv,
+    /// A table in an SQLite database, or one for each table of the layout
+    Sqlite,
+}
+
+impl Format {
+    /// The extension for files named by us, in split or normalized output.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Parquet => "parquet",
+            Format::Ipc => "arrow",
+            Format::IpcStream => "arrows",
+            Format::Json => "ndjson",
+            Format::Csv => "csv",
+            Format::Sqlite => "sqlite",
+        }
+    }
+}
+
+/// What releases are split into directories by, see `partition`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionBy {
+    Year,
+    Country,
+    /// Ranges of ids of the given width
+    IdRange(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    pub format: Format,
+    pub layout: Layout,
+    pub partition_by: Option<PartitionBy>,
+    /// Start a new file once one has grown to roughly this many bytes
+    pub max_file_size: Option<usize>,
+    /// How many records go into each Arrow batch
+    pub batch_size: usize,
+    /// What goes between the items of a list in CSV
+    pub list_separator: String,
+    pub compression: Compression,
+    pub max_row_group_size: Option<usize>,
+    pub data_page_size: Option<usize>,
+    pub writer_version: WriterVersion,
+    /// Whether to dictionary encode, for all columns or just the one named,
+    /// applied in order
+    pub dictionary: Vec<(Option<ColumnPath>, bool)>,
+    /// The statistics to keep, likewise
+    pub statistics: Vec<(Option<ColumnPath>, EnabledStatistics)>,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            format: Format::Parquet,
+            layout: Layout::Nested,
+            partition_by: None,
+            max_file_size: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            list_separator: ";".to_owned(),
+            compression: Compression::SNAPPY,
+            max_row_group_size: None,
+            data_page_size: None,
+            writer_version: WriterVersion::PARQUET_1_0,
+            dictionary: Vec::new(),
+            statistics: Vec::new(),
+        }
+    }
+}
+
+impl OutputOptions {
+    pub fn writer_properties(&self) -> WriterProperties {
+        let mut builder = WriterProperties::builder()
+            .set_compression(self.compression)
+            .set_writer_version(self.writer_version)
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                "discogs_schema_version".to_owned(),
+                SCHEMA_VERSION.to_string(),
+            )]));
+
+        if let Some(max_row_group_size) = self.max_row_group_size {
+            builder = builder.set_max_row_group_size(max_row_group_size);
+        }
+        if let Some(data_page_size) = self.data_page_size {
+            builder = builder.set_data_page_size_limit(data_page_size);
+        }
+        for (column, enabled) in &self.dictionary {
+            builder = match column {
+                Some(column) => builder.set_column_dictionary_enabled(column.clone(), *enabled),
+                None => builder.set_dictionary_enabled(*enabled),
+            };
+        }
+        for (column, statistics) in &self.statistics {
+            builder = match column {
+                Some(column) => builder.set_column_statistics_enabled(column.clone(), *statistics),
+                None => builder.set_statistics_enabled(*statistics),
+            };
+        }
+
+        builder.build()
+    }
+}
+
+pub fn parse_format(value: &str) -> Option<Format> {
+    match value {
+        "parquet" => Some(Format::Parquet),
+        "ipc" | "feather" => Some(Format::Ipc),
+        "ipc-stream" => Some(Format::IpcStream),
+        "ndjson" | "jsonl" => Some(Format::Json),
+        "csv" => Some(Format::Csv),
+        "sqlite" => Some(Format::Sqlite),
+        _ => None,
+    }
+}
+
+pub fn parse_layout(value: &str) -> Option<Layout> {
+    match value {
+        "nested" => Some(Layout::Nested),
+        "normalized" => Some(Layout::Normalized),
+        _ => None,
+    }
+}
+
+/// Either `year`, `country`, or `id:n` for ranges of `n` ids.
+pub fn parse_partition_by(value: &str) -> Option<PartitionBy> {
+    match value.split_once(':') {
+        Some(("id", width)) => Some(PartitionBy::IdRange(width.parse().ok().filter(|&w| w > 0)?)),
+        Some(_) => None,
+        None => match value {
+            "year" => Some(PartitionBy::Year),
+            "country" => Some(PartitionBy::Country),
+            _ => None,
+        },
+    }
+}
+
+/// A codec, with a level for those that have them, e.g. `zstd:9`.
+pub fn parse_compression(value: &str) -> Option<Compression> {
+    let (codec, level) = match value.split_once(':') {
+        Some((codec, level)) => (codec, Some(level)),
+        None => (value, None),
+    };
+
+    let compression = match (codec, level) {
+        ("none", None) => Compression::UNCOMPRESSED,
+        ("snappy", None) => Compression::SNAPPY,
+        ("lz4", None) => Compression::LZ4_RAW,
+        ("zstd", None) => Compression::ZSTD(ZstdLevel::default()),
+        ("zstd", Some(level)) => Compression::ZSTD(ZstdLevel::try_new(level.parse().ok()?).ok()?),
+        ("gzip", None) => Compression::GZIP(GzipLevel::default()),
+        ("gzip", Some(level)) => Compression::GZIP(GzipLevel::try_new(level.parse().ok()?).ok()?),
+        ("brotli", None) => Compression::BROTLI(BrotliLevel::default()),
+        ("brotli", Some(level)) => {
+            Compression::BROTLI(BrotliLevel::try_new(level.parse().ok()?).ok()?)
+        }
+        _ => return None,
+    };
+    Some(compression)
+}
+
+pub fn parse_writer_version(value: &str) -> Option<WriterVersion> {
+    match value {
+        "1.0" => Some(WriterVersion::PARQUET_1_0),
+        "2.0" => Some(WriterVersion::PARQUET_2_0),
+        _ => None,
+    }
+}
+
+pub fn parse_dictionary(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+pub fn parse_statistics(value: &str) -> Option<EnabledStatistics> {
+    match value {
+        "none" => Some(EnabledStatistics::None),
+        "chunk" => Some(EnabledStatistics::Chunk),
+        "page" => Some(EnabledStatistics::Page),
+        _ => None,
+    }
+}
+
+/// A setting for all columns, or for one given as `column=setting`. Nested
+/// columns are named by their path in the Parquet schema, with dots between,
+/// e.g. `labels.list.item.catno`.
+pub fn parse_column_setting<T>(
+    value: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<(Option<ColumnPath>, T)> {
+    match value.rsplit_once('=') {
+        Some((column, setting)) => {
+            let column = ColumnPath::new(column.split('.').map(String::from).collect());
+            Some((Some(column), parse(setting)?))
+        }
+        None => Some((None, parse(value)?)),
+    }
+}
@@ -0,0 +1,508 @@
+//! Output split across many files, so that it can be pruned and rewritten a
+//! piece at a time. Releases go in Hive style directories named after the
+//! partition they fall in, e.g. `released_year=1994/part-0000.parquet`, and a
+//! partition gets a new part before its file would grow past a target size.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use arrow::array::{AsArray, UInt32Array};
+use arrow::compute::{cast, take};
+use arrow::datatypes::{DataType, Int16Type, Schema, UInt32Type};
+use arrow::record_batch::RecordBatch;
+
+use crate::error::ProcessingError;
+use crate::output::{OutputOptions, PartitionBy};
+use crate::sink::{self, Sink};
+
+/// Where releases go when the value they're partitioned by is missing.
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Characters that Hive escapes in directory names, besides control characters.
+const ESCAPED: &str = "\"#%'*/:=?\\{[]^";
+
+// Each file being written holds on to a row group until it is full, so
+// there's a limit on how much they can hold between them, and on how many can
+// be open at once
+const MAX_OPEN_FILES: usize = 16;
+const MAX_BUFFERED: usize = 268435456; // 256MB
+
+// How many rows are written to a file that's thrown away, to see how big they
+// come out
+const SAMPLE_ROWS: usize = 1000;
+
+impl PartitionBy {
+    /// The column that is left out of the files, as its value is in their path.
+    pub fn column(&self) -> Option<&'static str> {
+        match self {
+            PartitionBy::Year => Some("released_year"),
+            PartitionBy::Country => Some("country"),
+            PartitionBy::IdRange(_) => None,
+        }
+    }
+
+    fn key(&self) -> &'static str {
+        match self {
+            PartitionBy::Year => "released_year",
+            PartitionBy::Country => "country",
+            PartitionBy::IdRange(_) => "id_bucket",
+        }
+    }
+}
+
+/// Split a batch of nested releases by partition, each named by the path of
+/// its directory.
+pub fn split(
+    batch: &RecordBatch,
+    by: PartitionBy,
+) -> Result<Vec<(String, RecordBatch)>, ProcessingError> {
+    let column = |name| {
+        let column = batch.column_by_name(name);
+        column.expect("releases are batched with every column of the release schema")
+    };
+    let values: Vec<Option<String>> = match by {
+        PartitionBy::Year => {
+            let years = column("released_year");
+            let years = years.as_primitive::<Int16Type>();
+            years
+                .iter()
+                .map(|year| year.map(|y| y.to_string()))
+                .collect()
+        }
+        PartitionBy::Country => {
+            let countries = cast(column("country"), &DataType::Utf8)?;
+            let countries = countries.as_string::<i32>();
+            countries.iter().map(|c| c.map(str::to_owned)).collect()
+        }
+        PartitionBy::IdRange(width) => {
+            let ids = column("id");
+            let ids = ids.as_primitive::<UInt32Type>();
+            ids.iter()
+                .map(|id| id.map(|id| (id / width * width).to_string()))
+                .collect()
+        }
+    };
+
+    let mut partitions: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for (row, value) in values.into_iter().enumerate() {
+        let value = match value.as_deref() {
+            None | Some("") => DEFAULT_PARTITION.to_owned(),
+            Some(value) => escape(value),
+        };
+        let partition = format!("{}={value}", by.key());
+        partitions.entry(partition).or_default().push(row as u32);
+    }
+
+    // Most batches of a dump in id order fall in a single id range
+    if partitions.len() == 1 {
+        let partition = partitions.into_keys().next().unwrap();
+        return Ok(vec![(partition, batch.clone())]);
+    }
+
+    (partitions.into_iter())
+        .map(|(partition, rows)| Ok((partition, take_rows(batch, &UInt32Array::from(rows))?)))
+        .collect()
+}
+
+fn take_rows(batch: &RecordBatch, rows: &UInt32Array) -> Result<RecordBatch, ProcessingError> {
+    let columns = (batch.columns().iter())
+        .map(|column| take(column, rows, None))
+        .collect::<Result<_, _>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+/// Escape a value for use in a directory name, the same way Hive does.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii_control() || ESCAPED.contains(c) {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Leave out the column that `by` puts in the path, if the schema has it.
+pub fn strip_schema(
+    schema: &Arc<Schema>,
+    by: Option<PartitionBy>,
+) -> Result<Arc<Schema>, ProcessingError> {
+    match by.and_then(|by| by.column()) {
+        Some(column) => match schema.index_of(column) {
+            Ok(i) => {
+                let kept: Vec<_> = (0..schema.fields().len()).filter(|&j| j != i).collect();
+                Ok(Arc::new(schema.project(&kept)?))
+            }
+            Err(_) => Ok(schema.clone()),
+        },
+        None => Ok(schema.clone()),
+    }
+}
+
+/// Leave out the column that `by` puts in the path, if the batch has it.
+pub fn strip(batch: RecordBatch, by: Option<PartitionBy>) -> Result<RecordBatch, ProcessingError> {
+    match by.and_then(|by| by.column()) {
+        Some(column) => match batch.schema().index_of(column) {
+            Ok(i) => {
+                let kept: Vec<_> = (0..batch.num_columns()).filter(|&j| j != i).collect();
+                Ok(batch.project(&kept)?)
+            }
+            Err(_) => Ok(batch),
+        },
+        None => Ok(batch),
+    }
+}
+
+/// A file that is only open while it is being written to. Parquet row groups
+/// are built up in memory and written out in one go, so the file can be closed
+/// in between, which lets there be more partitions than files that can be open.
+#[derive(Clone)]
+struct PartFile {
+    path: PathBuf,
+    state: Arc<Mutex<PartFileState>>,
+}
+
+struct PartFileState {
+    file: Option<File>,
+    written: usize,
+}
+
+impl PartFile {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = File::create(&path)?;
+        Ok(PartFile {
+            path,
+            state: Arc::new(Mutex::new(PartFileState {
+                file: Some(file),
+                written: 0,
+            })),
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        self.state.lock().unwrap().file.is_some()
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().file.take();
+    }
+
+    fn written(&self) -> usize {
+        self.state.lock().unwrap().written
+    }
+}
+
+impl Write for PartFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let file = match &mut state.file {
+            Some(file) => file,
+            None => (state.file).insert(OpenOptions::new().append(true).open(&self.path)?),
+        };
+        let written = file.write(buf)?;
+        state.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state.lock().unwrap().file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Counts what's written to it, and throws it away.
+#[derive(Clone, Default)]
+struct ByteCounter(Arc<AtomicUsize>);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.fetch_add(buf.len(), Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The file being written for a partition.
+struct Part {
+    writer: Box<dyn Sink>,
+    file: PartFile,
+    rows: usize,
+    last_used: usize,
+}
+
+/// Writes a table as parts in a directory of partitions.
+pub struct PartitionedWriter {
+    dir: PathBuf,
+    schema: Arc<Schema>,
+    options: OutputOptions,
+    /// The part being written for each partition
+    parts: BTreeMap<String, Part>,
+    /// The number of the next part of each partition
+    next_part: BTreeMap<String, usize>,
+    /// Roughly how many bytes a file takes with no rows in it, and how many
+    /// each row adds, once measured
+    sizes: Option<(usize, usize)>,
+    writes: usize,
+}
+
+impl PartitionedWriter {
+    pub fn new(dir: PathBuf, schema: Arc<Schema>, options: &OutputOptions) -> Self {
+        PartitionedWriter {
+            dir,
+            schema,
+            options: options.clone(),
+            parts: BTreeMap::new(),
+            next_part: BTreeMap::new(),
+            sizes: None,
+            writes: 0,
+        }
+    }
+
+    /// Write a batch to the partition with the given path, which is empty if
+    /// the files are only split by size.
+    pub fn write(&mut self, partition: &str, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        self.writes += 1;
+
+        if self.options.max_file_size.is_some() && self.sizes.is_none() && batch.num_rows() > 0 {
+            self.sizes = Some(self.measure(batch)?);
+        }
+
+        let mut written = 0;
+        while written < batch.num_rows() {
+            if !self.parts.contains_key(partition) {
+                let part = self.create(partition)?;
+                self.parts.insert(partition.to_owned(), part);
+            }
+            let part = self.parts.get_mut(partition).unwrap();
+
+            // Only as many rows go in as there look to be room for, and the
+            // rest start a new part. Rows are taken to be as big as those
+            // already in the part if that's bigger than measured, but as rows
+            // vary, a file can still come out a little over or under.
+            let rows = match (self.options.max_file_size, self.sizes) {
+                (Some(max_file_size), Some((overhead, row_size))) => {
+                    let size = part.file.written() + part.writer.buffered();
+                    let row_size = row_size.max(size / part.rows.max(1)).max(1);
+                    let fit = max_file_size.saturating_sub(overhead) / row_size;
+                    let room = fit.saturating_sub(part.rows);
+                    if room == 0 && part.rows > 0 {
+                        let part = self.parts.remove(partition).unwrap();
+                        part.writer.close()?;
+                        continue;
+                    }
+                    // A row that's bigger than a file can be gets one to itself
+                    room.clamp(1, batch.num_rows() - written)
+                }
+                _ => batch.num_rows(),
+            };
+
+            if rows == batch.num_rows() {
+                part.writer.write(batch)?;
+            } else {
+                // Copied rather than sliced, as the IPC writers write out the
+                // whole of what a slice of a list points into
+                let range = written as u32..(written + rows) as u32;
+                let rows = take_rows(batch, &UInt32Array::from_iter_values(range))?;
+                part.writer.write(&rows)?;
+            }
+            written += rows;
+            part.rows += rows;
+            part.last_used = self.writes;
+        }
+
+        let buffered: usize = (self.parts.values())
+            .map(|part| part.writer.buffered())
+            .sum();
+        if buffered > MAX_BUFFERED {
+            let part = (self.parts.values_mut())
+                .max_by_key(|part| part.writer.buffered())
+                .unwrap();
+            part.writer.flush()?;
+        }
+
+        self.close_idle_files();
+
+        Ok(())
+    }
+
+    fn create(&mut self, partition: &str) -> Result<Part, ProcessingError> {
+        let dir = self.dir.join(partition);
+        fs::create_dir_all(&dir)?;
+
+        let next_part = self.next_part.entry(partition.to_owned()).or_default();
+        let extension = self.options.format.extension();
+        let file = PartFile::create(dir.join(format!("part-{next_part:04}.{extension}")))?;
+        *next_part += 1;
+
+        let writer = sink::create(file.clone(), self.schema.clone(), &self.options)?;
+        Ok(Part {
+            writer,
+            file,
+            rows: 0,
+            last_used: self.writes,
+        })
+    }
+
+    /// Write the first row of `batch`, then the first few, to files that are
+    /// thrown away, to see how big files come out once they're finished and
+    /// compressed. A file with a row in it has everything that any file has
+    /// besides its rows, which for Parquet is a good deal more than an empty
+    /// one, so it's taken as the size of a file with no rows.
+    fn measure(&self, batch: &RecordBatch) -> Result<(usize, usize), ProcessingError> {
+        let first = take_rows(batch, &UInt32Array::from(vec![0]))?;
+        let overhead = self.finished_size(&first)?;
+        let rows = batch.num_rows().min(SAMPLE_ROWS);
+        let sample = take_rows(batch, &UInt32Array::from_iter_values(0..rows as u32))?;
+        let sample = self.finished_size(&sample)?;
+        let row_size = sample.saturating_sub(overhead) / (rows - 1).max(1);
+        Ok((overhead, row_size))
+    }
+
+    fn finished_size(&self, batch: &RecordBatch) -> Result<usize, ProcessingError> {
+        let counter = ByteCounter::default();
+        let mut writer = sink::create(counter.clone(), self.schema.clone(), &self.options)?;
+        writer.write(batch)?;
+        writer.close()?;
+        Ok(counter.0.load(Ordering::Relaxed))
+    }
+
+    /// Close the files that have gone longest without being written to, if
+    /// too many are open. They are opened again when next written to.
+    fn close_idle_files(&mut self) {
+        let mut open: Vec<_> = (self.parts.values())
+            .filter(|part| part.file.is_open())
+            .collect();
+        if open.len() > MAX_OPEN_FILES {
+            open.sort_by_key(|part| part.last_used);
+            for part in &open[..open.len() - MAX_OPEN_FILES] {
+                part.file.close();
+            }
+        }
+    }
+
+    pub fn close(self) -> Result<(), ProcessingError> {
+        for part in self.parts.into_values() {
+            part.writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::release::parse_batch;
+
+    fn release(id: u32, extra: &str) -> String {
+        format!(r#"<release id="{id}" status="Accepted"><title>T</title>{extra}</release>"#)
+    }
+
+    /// The ids of the releases in each partition.
+    fn partitions(releases: &[String], by: PartitionBy) -> Vec<(String, Vec<u32>)> {
+        let releases: Vec<_> = releases.iter().map(String::as_str).collect();
+        let batch = parse_batch(&releases);
+        (split(&batch, by).unwrap().into_iter())
+            .map(|(partition, batch)| {
+                let ids = batch.column_by_name("id").unwrap();
+                let ids = ids.as_primitive::<UInt32Type>().values().to_vec();
+                (partition, ids)
+            })
+            .collect()
+    }
+
+    fn partition(name: &str, ids: &[u32]) -> (String, Vec<u32>) {
+        (name.to_owned(), ids.to_vec())
+    }
+
+    #[test]
+    fn by_year() {
+        let releases = [
+            release(1, "<released>1994-01-01</released>"),
+            release(2, "<released>2001</released>"),
+            release(3, ""),
+            release(4, "<released>1994</released>"),
+            release(5, "<released>Unknown</released>"),
+        ];
+        assert_eq!(
+            partitions(&releases, PartitionBy::Year),
+            [
+                partition("released_year=1994", &[1, 4]),
+                partition("released_year=2001", &[2]),
+                partition("released_year=__HIVE_DEFAULT_PARTITION__", &[3, 5]),
+            ]
+        );
+    }
+
+    #[test]
+    fn by_country() {
+        let releases = [
+            release(1, "<country>UK</country>"),
+            release(2, "<country>Serbia/Montenegro</country>"),
+            release(3, "<country></country>"),
+            release(4, ""),
+            release(5, "<country>UK</country>"),
+        ];
+        assert_eq!(
+            partitions(&releases, PartitionBy::Country),
+            [
+                partition("country=Serbia%2FMontenegro", &[2]),
+                partition("country=UK", &[1, 5]),
+                partition("country=__HIVE_DEFAULT_PARTITION__", &[3, 4]),
+            ]
+        );
+    }
+
+    #[test]
+    fn by_id_range() {
+        let releases = [1, 999, 1000, 2500, 1999].map(|id| release(id, ""));
+        assert_eq!(
+            partitions(&releases, PartitionBy::IdRange(1000)),
+            [
+                partition("id_bucket=0", &[1, 999]),
+                partition("id_bucket=1000", &[1000, 1999]),
+                partition("id_bucket=2000", &[2500]),
+            ]
+        );
+
+        // All in one range, as most batches are
+        let releases = [1, 2, 3].map(|id| release(id, ""));
+        let expected = [partition("id_bucket=0", &[1, 2, 3])];
+        assert_eq!(partitions(&releases, PartitionBy::IdRange(10)), expected);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("Serbia/Montenegro"), "Serbia%2FMontenegro");
+        assert_eq!(escape("a=b"), "a%3Db");
+        assert_eq!(escape("100%"), "100%25");
+        assert_eq!(escape("Côte d'Ivoire"), "Côte d%27Ivoire");
+        assert_eq!(escape("tab\there"), "tab%09here");
+        assert_eq!(escape("UK & Europe"), "UK & Europe");
+    }
+
+    #[test]
+    fn stripping_the_partition_column() {
+        let batch = parse_batch(&[&release(1, "<released>1994</released>")]);
+        let schema = strip_schema(&batch.schema(), Some(PartitionBy::Year)).unwrap();
+        let stripped = strip(batch.clone(), Some(PartitionBy::Year)).unwrap();
+        assert_eq!(stripped.schema(), schema);
+        assert!(schema.column_with_name("released_year").is_none());
+        assert_eq!(stripped.num_columns(), batch.num_columns() - 1);
+
+        // Ids are kept, as the bucket is only a range of them
+        let stripped = strip(batch.clone(), Some(PartitionBy::IdRange(10))).unwrap();
+        assert_eq!(stripped.schema(), batch.schema());
+    }
+}
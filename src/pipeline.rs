@@ -0,0 +1,238 @@
+//! Converting a dump on several threads. One thread decompresses, another
+//! splits the XML into chunks of records, a pool of workers parses the chunks
+//! into batches, and the calling thread writes the batches out in order, so
+//! the output is the same however many workers there are.
+
+use memchr::memmem;
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Cursor, Read};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::ProcessingError;
+use crate::reader::{EventExt, EventReader};
+
+const BLOCK_SIZE: usize = 1048576; // 1MB
+
+// How many decompressed blocks can be waiting to be split
+const BLOCKS_IN_FLIGHT: usize = 16;
+
+// How many chunks each worker can have taken on, being parsed or waiting to be
+// written
+const CHUNKS_IN_FLIGHT_PER_THREAD: usize = 2;
+
+/// A run of whole records, wrapped in the start tag of the root element so
+/// that it can be parsed on its own.
+pub struct Chunk {
+    /// Where the chunk starts within the whole dump, counting the start tag
+    pub offset: usize,
+    pub xml: Vec<u8>,
+    pub records: usize,
+}
+
+impl Chunk {
+    /// Read the chunk, from just after the start tag of the root element.
+    pub fn reader(self, root: &'static str) -> Result<EventReader, ProcessingError> {
+        let mut reader = EventReader::from_reader(Cursor::new(self.xml)).with_offset(self.offset);
+        reader.advance()?.expect_start_of(root)?;
+        Ok(reader)
+    }
+}
+
+/// Convert the records in `root`, named `record`, whose start tag `reader` has
+/// just read. Each record must be followed by a newline, as they are in the
+/// Discogs dumps, which lets the XML be split into records without parsing it.
+/// Each chunk of `batch_size` records is parsed on one of `threads` workers.
+///
+/// When done, the reader carries on from just after the end tag of `root`.
+pub fn run<T, P, W>(
+    reader: &mut EventReader,
+    root: &'static str,
+    record: &'static str,
+    threads: usize,
+    batch_size: usize,
+    parse: P,
+    mut write: W,
+) -> Result<(), ProcessingError>
+where
+    T: Send,
+    P: Fn(Chunk) -> Result<T, ProcessingError> + Sync,
+    W: FnMut(T) -> Result<(), ProcessingError>,
+{
+    let offset = reader.position().offset;
+    let input = reader.input();
+
+    // The records start on the line after the start tag
+    if input.fill_buf()?.first() != Some(&b'\n') {
+        return Err(ProcessingError::expected_newline());
+    }
+    input.consume(1);
+    let offset = offset + 1;
+
+    let (tail, tail_offset) = thread::scope(|scope| {
+        let (blocks, blocks_rx) = sync_channel(BLOCKS_IN_FLIGHT);
+        let decompressing = scope.spawn(move || decompress(input, blocks));
+
+        let (chunks, chunks_rx) = sync_channel(threads);
+        let splitting =
+            scope.spawn(move || split(blocks_rx, chunks, root, record, batch_size, offset));
+
+        // A chunk holds on to a slot from when a worker takes it until it is
+        // written, so that however slow one chunk is, only so many finished
+        // ones can pile up behind it
+        let in_flight = threads * CHUNKS_IN_FLIGHT_PER_THREAD;
+        let (slots, slots_rx) = sync_channel(in_flight);
+        for _ in 0..in_flight {
+            slots.send(()).unwrap();
+        }
+        let slots_rx = Arc::new(Mutex::new(slots_rx));
+
+        // Workers take chunks as they become free, and send back what they
+        // made of them along with the chunk's place in line
+        let chunks_rx = Arc::new(Mutex::new(chunks_rx));
+        let (results, results_rx) = sync_channel(threads);
+        for _ in 0..threads {
+            let slots_rx = Arc::clone(&slots_rx);
+            let chunks_rx = Arc::clone(&chunks_rx);
+            let results = results.clone();
+            let parse = &parse;
+            scope.spawn(move || loop {
+                if slots_rx.lock().unwrap().recv().is_err() {
+                    break;
+                }
+                let Ok((i, chunk)) = chunks_rx.lock().unwrap().recv() else {
+                    break;
+                };
+                if results.send((i, parse(chunk))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(slots_rx);
+        drop(chunks_rx);
+        drop(results);
+
+        // Returning early drops the receiving ends, which stops the other threads
+        let mut next = 0;
+        let mut waiting = BTreeMap::new();
+        for (i, result) in results_rx {
+            waiting.insert(i, result);
+            while let Some(result) = waiting.remove(&next) {
+                write(result?)?;
+                next += 1;
+                // Only ever as many as were taken, so there's always room
+                let _ = slots.send(());
+            }
+        }
+
+        decompressing.join().unwrap();
+        splitting.join().unwrap()
+    })?;
+
+    // Put the start tag of the root back in front of what was left over, so
+    // that the reader can check that the root ends where it should
+    let mut rest = format!("<{root}>").into_bytes();
+    let tail_offset = tail_offset.saturating_sub(rest.len());
+    rest.extend(tail);
+    *reader = EventReader::from_reader(Cursor::new(rest)).with_offset(tail_offset);
+    reader.advance()?.expect_start_of(root)?;
+    reader.advance()?.expect_end_of(root)?;
+
+    Ok(())
+}
+
+/// Read the input in blocks. Any decompression happens as it is read.
+fn decompress(input: &mut (dyn BufRead + Send), blocks: SyncSender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        match (&mut *input)
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut block)
+        {
+            Ok(0) => break,
+            Ok(_) => {
+                if blocks.send(Ok(block)).is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                let _ = blocks.send(Err(err));
+                break;
+            }
+        }
+    }
+}
+
+/// Cut the XML into chunks of `batch_size` records, and hand back whatever is
+/// left after the last record, with where it starts.
+fn split(
+    blocks: Receiver<io::Result<Vec<u8>>>,
+    chunks: SyncSender<(usize, Chunk)>,
+    root: &'static str,
+    record: &'static str,
+    batch_size: usize,
+    mut offset: usize,
+) -> Result<(Vec<u8>, usize), ProcessingError> {
+    let start_tag = format!("<{root}>").into_bytes();
+    let end_of_record = format!("</{record}>\n").into_bytes();
+    let finder = memmem::Finder::new(&end_of_record);
+
+    let mut i = 0;
+    let mut chunk = start_tag.clone();
+    let mut records = 0;
+    // How far into the chunk the last record goes, and how far we've looked
+    let mut end = chunk.len();
+    let mut searched = chunk.len();
+
+    for block in blocks {
+        chunk.extend_from_slice(&block?);
+
+        while let Some(found) = finder.find(&chunk[searched..]) {
+            searched += found + end_of_record.len();
+            end = searched;
+            records += 1;
+
+            if records == batch_size {
+                let mut rest = start_tag.clone();
+                rest.extend_from_slice(&chunk[end..]);
+                let mut xml = std::mem::replace(&mut chunk, rest);
+                xml.truncate(end);
+
+                let full = Chunk {
+                    offset: offset.saturating_sub(start_tag.len()),
+                    xml,
+                    records,
+                };
+                if chunks.send((i, full)).is_err() {
+                    // The writer has stopped, and will say why
+                    return Ok((Vec::new(), offset));
+                }
+
+                i += 1;
+                offset += end - start_tag.len();
+                records = 0;
+                end = start_tag.len();
+                searched = start_tag.len();
+            }
+        }
+
+        // The end of a record can be split between blocks
+        searched = searched.max(chunk.len().saturating_sub(end_of_record.len() - 1));
+    }
+
+    let tail = chunk.split_off(end);
+    let tail_offset = offset + end - start_tag.len();
+    if records > 0 {
+        let last = Chunk {
+            offset: offset.saturating_sub(start_tag.len()),
+            xml: chunk,
+            records,
+        };
+        // If the writer has stopped, it will say why
+        let _ = chunks.send((i, last));
+    }
+
+    Ok((tail, tail_offset))
+}
@@ -0,0 +1,336 @@
+use bzip2::bufread::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use quick_xml::events::{BytesStart, BytesText, Event};
+use quick_xml::reader::Reader;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Deref;
+
+use crate::error::{unescape, Position, ProcessingError};
+use crate::gzip::ParallelGzDecoder;
+
+const READ_BUF_SIZE: usize = 1048576; // 1MB
+
+pub struct EventReader {
+    reader: Reader<Box<dyn BufRead + Send>>,
+    /// Where the input starts within the whole dump
+    offset: usize,
+    buf: Vec<u8>,
+    text: String,
+    path: ElementPath,
+    record_id: Option<u32>,
+    is_recording: bool,
+    recording: Vec<u8>,
+}
+
+/// The names of the elements we're currently inside of, kept so that errors
+/// can say where they happened.
+#[derive(Default)]
+struct ElementPath {
+    path: Vec<u8>,
+    lengths: Vec<usize>,
+    /// An empty element is only "entered" until the next event is read
+    in_empty: bool,
+}
+
+impl ElementPath {
+    fn track(&mut self, event: &Event) {
+        if self.in_empty {
+            self.pop();
+            self.in_empty = false;
+        }
+
+        match event {
+            Event::Start(e) => self.push(e.name().into_inner()),
+            Event::Empty(e) => {
+                self.push(e.name().into_inner());
+                self.in_empty = true;
+            }
+            Event::End(_) => self.pop(),
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, name: &[u8]) {
+        self.lengths.push(self.path.len());
+        if !self.path.is_empty() {
+            self.path.push(b'/');
+        }
+        self.path.extend_from_slice(name);
+    }
+
+    fn pop(&mut self) {
+        if let Some(length) = self.lengths.pop() {
+            self.path.truncate(length);
+        }
+    }
+}
+
+/// How a dump has been compressed, if at all.
+enum InputCompression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    None,
+}
+
+impl InputCompression {
+    /// Tell from the magic bytes at the start of the input.
+    fn detect(start: &[u8]) -> Self {
+        if start.starts_with(&[0x1f, 0x8b]) {
+            InputCompression::Gzip
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            InputCompression::Zstd
+        } else if start.starts_with(b"BZh") {
+            InputCompression::Bzip2
+        } else {
+            InputCompression::None
+        }
+    }
+}
+
+impl EventReader {
+    /// Open a dump, which can be compressed with gzip, zstd or bzip2, or not at
+    /// all. A path of `-` reads from stdin. Given more than one thread, gzip is
+    /// decompressed on that many.
+    pub fn new(file_path: String, gzip_threads: usize) -> Result<Self, ProcessingError> {
+        let input: Box<dyn Read + Send> = if file_path == "-" {
+            Box::new(io::stdin())
+        } else {
+            Box::new(File::open(file_path)?)
+        };
+        let mut input = BufReader::with_capacity(READ_BUF_SIZE, input);
+
+        let reader = match InputCompression::detect(input.fill_buf()?) {
+            InputCompression::Gzip if gzip_threads > 1 => {
+                Self::from_reader(BufReader::new(ParallelGzDecoder::new(input, gzip_threads)))
+            }
+            // Like the parallel decoder, read on past the first member, as
+            // written by `pigz` or by concatenating dumps
+            InputCompression::Gzip => Self::from_reader(BufReader::new(MultiGzDecoder::new(input))),
+            InputCompression::Zstd => {
+                Self::from_reader(BufReader::new(zstd::Decoder::with_buffer(input)?))
+            }
+            // Parallel compressors write one bzip2 stream per block
+            InputCompression::Bzip2 => {
+                Self::from_reader(BufReader::new(MultiBzDecoder::new(input)))
+            }
+            InputCompression::None => Self::from_reader(input),
+        };
+        Ok(reader)
+    }
+
+    /// Read already decompressed XML from anywhere, e.g. recorded earlier on.
+    pub fn from_reader(input: impl BufRead + Send + 'static) -> Self {
+        let input: Box<dyn BufRead + Send> = Box::new(input);
+        EventReader {
+            reader: Reader::from_reader(input),
+            offset: 0,
+            buf: Vec::new(),
+            text: String::new(),
+            path: ElementPath::default(),
+            record_id: None,
+            is_recording: false,
+            recording: Vec::new(),
+        }
+    }
+
+    pub fn advance(&mut self) -> Result<Event<'_>, ProcessingError> {
+        self.buf.clear();
+        let event = self.reader.read_event_into(&mut self.buf)?;
+        self.path.track(&event);
+        if self.is_recording {
+            record(&mut self.recording, &event);
+        }
+        Ok(event)
+    }
+
+    /// Start copying the XML of the events read, so that it can be replayed
+    /// or set aside later on.
+    pub fn start_recording(&mut self) {
+        self.is_recording = true;
+    }
+
+    /// The XML recorded so far, which can be cleared to forget events.
+    pub fn recording(&mut self) -> &mut Vec<u8> {
+        &mut self.recording
+    }
+
+    /// How many elements we're currently inside of.
+    pub fn depth(&self) -> usize {
+        self.path.lengths.len() - usize::from(self.path.in_empty)
+    }
+
+    /// For input that is part of a bigger dump, the offset it starts at, so
+    /// that errors give the offset within the whole dump.
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// The rest of the input, as is. Reading from it directly leaves the
+    /// reader out of step.
+    pub fn input(&mut self) -> &mut (dyn BufRead + Send) {
+        self.reader.get_mut()
+    }
+
+    /// Set the id of the record being parsed, for error reporting.
+    pub fn set_record_id(&mut self, record_id: Option<u32>) {
+        self.record_id = record_id;
+    }
+
+    pub fn position(&self) -> Position {
+        Position {
+            offset: self.offset + self.reader.buffer_position(),
+            record_id: self.record_id,
+            path: String::from_utf8_lossy(&self.path.path).into_owned(),
+        }
+    }
+
+    /// Read the text content of an element whose start tag has just been
+    /// consumed, up to and including its end tag, with escapes replaced.
+    /// Returns `None` when the element has no content.
+    pub fn read_text(&mut self, name: &'static str) -> Result<Option<&str>, ProcessingError> {
+        self.buf.clear();
+        let event = self.reader.read_event_into(&mut self.buf)?;
+        self.path.track(&event);
+        if self.is_recording {
+            record(&mut self.recording, &event);
+        }
+        match event {
+            Event::End(e) if e.name().into_inner() == name.as_bytes() => return Ok(None),
+            Event::Text(e) => {
+                self.text.clear();
+                self.text.push_str(&unescape(&e)?);
+            }
+            _ => return Err(ProcessingError::expected_text()),
+        }
+
+        self.advance()?.expect_end_of(name)?;
+
+        Ok(Some(&self.text))
+    }
+
+    /// Consume events up to and including the end tag of `name`.
+    pub fn skip_to_end_of(&mut self, name: &'static str) -> Result<(), ProcessingError> {
+        loop {
+            if self.advance()?.is_end_of(name) {
+                break Ok(());
+            }
+        }
+    }
+}
+
+/// Write an event back out as XML. Text is kept escaped, so this gives back
+/// the XML as it was read, apart from whitespace inside tags.
+fn record(recording: &mut Vec<u8>, event: &Event) {
+    match event {
+        Event::Start(e) => {
+            recording.push(b'<');
+            recording.extend_from_slice(e);
+            recording.push(b'>');
+        }
+        Event::Empty(e) => {
+            recording.push(b'<');
+            recording.extend_from_slice(e);
+            recording.extend_from_slice(b"/>");
+        }
+        Event::End(e) => {
+            recording.extend_from_slice(b"</");
+            recording.extend_from_slice(e);
+            recording.push(b'>');
+        }
+        Event::Text(e) => recording.extend_from_slice(e),
+        Event::CData(e) => {
+            recording.extend_from_slice(b"<![CDATA[");
+            recording.extend_from_slice(e);
+            recording.extend_from_slice(b"]]>");
+        }
+        Event::Comment(e) => {
+            recording.extend_from_slice(b"<!--");
+            recording.extend_from_slice(e);
+            recording.extend_from_slice(b"-->");
+        }
+        Event::Decl(_) | Event::PI(_) | Event::DocType(_) | Event::Eof => {}
+    }
+}
+
+pub trait EventExt<'a> {
+    fn expect_start(self) -> Result<BytesStart<'a>, ProcessingError>;
+
+    fn expect_start_of(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError>;
+
+    fn expect_end_of(self, name: &'static str) -> Result<(), ProcessingError>;
+
+    fn expect_empty(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError>;
+
+    fn expect_new_line(self) -> Result<(), ProcessingError>;
+
+    fn expect_text(self) -> Result<BytesText<'a>, ProcessingError>;
+
+    fn expect_eof(self) -> Result<(), ProcessingError>;
+
+    fn is_end_of(&self, end_of: &'static str) -> bool;
+
+    fn is_empty_tag(&self) -> bool;
+}
+
+impl<'a> EventExt<'a> for Event<'a> {
+    fn expect_start_of(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError> {
+        match self {
+            Event::Start(e) if e.name().into_inner() == name.as_bytes() => Ok(e),
+            _ => Err(ProcessingError::expected_start_of(name)),
+        }
+    }
+
+    fn expect_start(self) -> Result<BytesStart<'a>, ProcessingError> {
+        match self {
+            Event::Start(e) => Ok(e),
+            _ => Err(ProcessingError::expected_start()),
+        }
+    }
+
+    fn expect_end_of(self, name: &'static str) -> Result<(), ProcessingError> {
+        match self {
+            Event::End(e) if e.name().into_inner() == name.as_bytes() => Ok(()),
+            _ => Err(ProcessingError::expected_end_of(name)),
+        }
+    }
+
+    fn expect_empty(self, name: &'static str) -> Result<BytesStart<'a>, ProcessingError> {
+        match self {
+            Event::Empty(e) if e.name().into_inner() == name.as_bytes() => Ok(e),
+            _ => Err(ProcessingError::expected_empty(name)),
+        }
+    }
+
+    fn expect_new_line(self) -> Result<(), ProcessingError> {
+        match self {
+            Event::Text(e) if e.deref() == b"\n" => Ok(()),
+            _ => Err(ProcessingError::expected_newline()),
+        }
+    }
+
+    fn expect_text(self) -> Result<BytesText<'a>, ProcessingError> {
+        match self {
+            Event::Text(e) => Ok(e),
+            _ => Err(ProcessingError::expected_text()),
+        }
+    }
+
+    fn expect_eof(self) -> Result<(), ProcessingError> {
+        match self {
+            Event::Eof => Ok(()),
+            _ => Err(ProcessingError::expected_eof()),
+        }
+    }
+
+    fn is_end_of(&self, name: &'static str) -> bool {
+        matches!(&self, Event::End(e) if e.name().into_inner() == name.as_bytes())
+    }
+
+    fn is_empty_tag(&self) -> bool {
+        matches!(&self, Event::Empty(_))
+    }
+}
@@ -0,0 +1,1789 @@
+use quick_xml::events::{attributes::Attribute, BytesStart, Event};
+use quick_xml::name::QName;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow::array::{
+    ArrayBuilder, BooleanBuilder, Date32Builder, Int16Builder, LargeStringBuilder, ListBuilder,
+    StringArray, StringBuilder, StringDictionaryBuilder, StructBuilder, UInt32Builder,
+};
+use arrow::datatypes::{DataType, Date32Type, Field, Fields, Int16Type, Int8Type, Schema};
+use arrow::record_batch::RecordBatch;
+
+use chrono::NaiveDate;
+
+use crate::common::{
+    artist_fields, artists_builder, data_quality_builder, extra_artist_fields,
+    extra_artists_builder, is_aligned, parse_artists, parse_extra_artists, parse_genres,
+    parse_images, parse_styles, parse_videos, struct_field, video_fields, videos_builder, Repeated,
+    Single,
+};
+use crate::error::{integer, unescape, unescape_value, OnDuplicate, OnError, ProcessingError};
+use crate::normalize;
+use crate::output::{Format, Layout, OutputOptions};
+use crate::partition::{self, PartitionedWriter};
+use crate::pipeline::{self, Chunk};
+use crate::reader::{EventExt, EventReader};
+use crate::sink::{self, Sink};
+
+//TODO: Figure out order, make consistent
+struct ReleaseBatchWriter {
+    pending: usize,
+    batch_size: usize,
+    //TODO: Lose plural where value in a single row is not a list
+    ids: UInt32Builder,
+    statuses: StringDictionaryBuilder<Int8Type>,
+    titles: StringBuilder,
+    artists: ListBuilder<StructBuilder>,
+    extra_artists: ListBuilder<StructBuilder>,
+    genres: ListBuilder<StringBuilder>,
+    styles: ListBuilder<StringBuilder>,
+    labels: ListBuilder<StructBuilder>,
+    is_main_release: BooleanBuilder,
+    master_id: UInt32Builder,
+    tracklist: ListBuilder<StructBuilder>,
+    formats: ListBuilder<StructBuilder>,
+    released: StringBuilder,
+    released_year: Int16Builder,
+    released_date: Date32Builder,
+    released_precision: StringDictionaryBuilder<Int8Type>,
+    identifiers: ListBuilder<StructBuilder>,
+    barcode: StringBuilder,
+    companies: ListBuilder<StructBuilder>,
+    country: StringDictionaryBuilder<Int16Type>,
+    data_quality: StringDictionaryBuilder<Int8Type>,
+    notes: LargeStringBuilder,
+    videos: ListBuilder<StructBuilder>,
+    current: Current,
+    repeated: Repeated,
+    /// Ids of the releases with repeated elements, by element
+    duplicates: BTreeMap<&'static str, BTreeSet<u32>>,
+    schema: Arc<Schema>,
+}
+
+/// The single valued columns of the release being parsed, held back until the
+/// end of the release.
+#[derive(Default)]
+struct Current {
+    id: Single<u32>,
+    status: Single<String>,
+    title: Single<String>,
+    is_main_release: Single<bool>,
+    master_id: Single<u32>,
+    released: Single<String>,
+    barcode: Single<String>,
+    country: Single<String>,
+    data_quality: Single<String>,
+    notes: Single<String>,
+}
+
+// Field positions within the track struct, sub tracks share the same layout
+// but have no sub_tracks of their own.
+const TRACK_POSITION: usize = 0;
+const TRACK_TYPE: usize = 1;
+const TRACK_TITLE: usize = 2;
+const TRACK_DURATION: usize = 3;
+const TRACK_ARTISTS: usize = 4;
+const TRACK_EXTRA_ARTISTS: usize = 5;
+const TRACK_SUB_TRACKS: usize = 6;
+
+// Field positions within the format struct
+const FORMAT_NAME: usize = 0;
+const FORMAT_QTY: usize = 1;
+const FORMAT_TEXT: usize = 2;
+const FORMAT_DESCRIPTIONS: usize = 3;
+
+// Field positions within the identifier struct
+const IDENTIFIER_TYPE: usize = 0;
+const IDENTIFIER_DESCRIPTION: usize = 1;
+const IDENTIFIER_VALUE: usize = 2;
+
+// Field positions within the company struct
+const COMPANY_ID: usize = 0;
+const COMPANY_NAME: usize = 1;
+const COMPANY_CATNO: usize = 2;
+const COMPANY_ENTITY_TYPE: usize = 3;
+const COMPANY_ENTITY_TYPE_NAME: usize = 4;
+
+pub fn sub_track_fields() -> Vec<Field> {
+    vec![
+        Field::new("position", DataType::Utf8, true),
+        Field::new("type_", DataType::Utf8, true),
+        Field::new("title", DataType::Utf8, true),
+        Field::new("duration", DataType::Utf8, true),
+        Field::new_list(
+            "artists",
+            Field::new_struct("item", artist_fields(), true),
+            false,
+        ),
+        Field::new_list(
+            "extra_artists",
+            Field::new_struct("item", extra_artist_fields(), true),
+            false,
+        ),
+    ]
+}
+
+pub fn label_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("id", DataType::UInt32, false),
+        Field::new("cat_no", DataType::Utf8, false),
+        Field::new("name", DataType::Utf8, false),
+    ])
+}
+
+fn track_fields() -> Fields {
+    let mut fields = sub_track_fields();
+    fields.push(Field::new_list(
+        "sub_tracks",
+        Field::new_struct("item", sub_track_fields(), true),
+        false,
+    ));
+    Fields::from(fields)
+}
+
+pub fn format_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("name", DataType::Utf8, true),
+        Field::new("qty", DataType::UInt32, true),
+        Field::new("text", DataType::Utf8, true),
+        Field::new_list(
+            "descriptions",
+            Field::new("item", DataType::Utf8, true),
+            false,
+        ),
+    ])
+}
+
+pub fn identifier_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("type", DataType::Utf8, true),
+        Field::new("description", DataType::Utf8, true),
+        Field::new("value", DataType::Utf8, true),
+    ])
+}
+
+pub fn company_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("id", DataType::UInt32, true),
+        Field::new("name", DataType::Utf8, true),
+        Field::new("catno", DataType::Utf8, true),
+        Field::new("entity_type", DataType::UInt32, true),
+        Field::new("entity_type_name", DataType::Utf8, true),
+    ])
+}
+
+fn sub_track_builders() -> Vec<Box<dyn ArrayBuilder>> {
+    vec![
+        Box::new(StringBuilder::new()), // position
+        Box::new(StringBuilder::new()), // type_
+        Box::new(StringBuilder::new()), // title
+        Box::new(StringBuilder::new()), // duration
+        Box::new(artists_builder()),
+        Box::new(extra_artists_builder()),
+    ]
+}
+
+fn tracklist_builder() -> ListBuilder<StructBuilder> {
+    let sub_tracks = ListBuilder::new(StructBuilder::new(
+        Fields::from(sub_track_fields()),
+        sub_track_builders(),
+    ));
+
+    let mut builders = sub_track_builders();
+    builders.push(Box::new(sub_tracks));
+
+    ListBuilder::new(StructBuilder::new(track_fields(), builders))
+}
+
+fn formats_builder() -> ListBuilder<StructBuilder> {
+    ListBuilder::new(StructBuilder::new(
+        format_fields(),
+        vec![
+            Box::new(StringBuilder::new()),                   // name
+            Box::new(UInt32Builder::new()),                   // qty
+            Box::new(StringBuilder::new()),                   // text
+            Box::new(ListBuilder::new(StringBuilder::new())), // descriptions
+        ],
+    ))
+}
+
+fn identifiers_builder() -> ListBuilder<StructBuilder> {
+    ListBuilder::new(StructBuilder::new(
+        identifier_fields(),
+        vec![
+            Box::new(StringBuilder::new()), // type
+            Box::new(StringBuilder::new()), // description
+            Box::new(StringBuilder::new()), // value
+        ],
+    ))
+}
+
+fn companies_builder() -> ListBuilder<StructBuilder> {
+    ListBuilder::new(StructBuilder::new(
+        company_fields(),
+        vec![
+            Box::new(UInt32Builder::new()), // id
+            Box::new(StringBuilder::new()), // name
+            Box::new(StringBuilder::new()), // catno
+            Box::new(UInt32Builder::new()), // entity_type
+            Box::new(StringBuilder::new()), // entity_type_name
+        ],
+    ))
+}
+
+pub fn release_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("id", DataType::UInt32, false),
+        //TODO: Is dictionary encoding actually useful/working?
+        Field::new(
+            "status",
+            DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+            false,
+        ),
+        Field::new("title", DataType::Utf8, false),
+        Field::new_list(
+            "artists",
+            Field::new_struct("item", artist_fields(), true),
+            false,
+        ),
+        Field::new_list(
+            "extra_artists",
+            Field::new_struct("item", extra_artist_fields(), true),
+            false,
+        ),
+        //TODO: Should we dictionary encode genres and styles?
+        //TODO: Can we verify which encoding is written?
+        Field::new_list("genres", Field::new("item", DataType::Utf8, true), false),
+        Field::new_list("styles", Field::new("item", DataType::Utf8, true), false),
+        Field::new_list(
+            "labels",
+            Field::new_struct("item", label_fields(), true),
+            false,
+        ),
+        Field::new("is_main_release", DataType::Boolean, true),
+        Field::new("master_id", DataType::UInt32, true),
+        Field::new_list(
+            "tracklist",
+            Field::new_struct("item", track_fields(), true),
+            false,
+        ),
+        Field::new_list(
+            "formats",
+            Field::new_struct("item", format_fields(), true),
+            false,
+        ),
+        Field::new("released", DataType::Utf8, true),
+        Field::new("released_year", DataType::Int16, true),
+        Field::new("released_date", DataType::Date32, true),
+        Field::new(
+            "released_precision",
+            DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+            true,
+        ),
+        Field::new_list(
+            "identifiers",
+            Field::new_struct("item", identifier_fields(), true),
+            false,
+        ),
+        Field::new("barcode", DataType::Utf8, true),
+        Field::new_list(
+            "companies",
+            Field::new_struct("item", company_fields(), true),
+            false,
+        ),
+        Field::new(
+            "country",
+            DataType::Dictionary(Box::new(DataType::Int16), Box::new(DataType::Utf8)),
+            true,
+        ),
+        Field::new(
+            "data_quality",
+            DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+            true,
+        ),
+        Field::new("notes", DataType::LargeUtf8, true),
+        Field::new_list(
+            "videos",
+            Field::new_struct("item", video_fields(), true),
+            false,
+        ),
+    ])
+}
+
+impl ReleaseBatchWriter {
+    fn new(schema: Arc<Schema>, on_duplicate: OnDuplicate, batch_size: usize) -> Self {
+        let status_values =
+            StringArray::from(vec![Some("Accepted"), Some("Draft"), Some("Deleted")]);
+
+        let precision_values = StringArray::from(vec![Some("year"), Some("month"), Some("day")]);
+
+        //TODO: Review default capacities.
+
+        ReleaseBatchWriter {
+            pending: 0,
+            batch_size,
+            ids: UInt32Builder::with_capacity(batch_size),
+            statuses: StringDictionaryBuilder::<Int8Type>::new_with_dictionary(3, &status_values)
+                .unwrap(),
+            titles: StringBuilder::with_capacity(batch_size, 512),
+            artists: artists_builder(),
+            extra_artists: extra_artists_builder(),
+            genres: ListBuilder::new(StringBuilder::new()),
+            styles: ListBuilder::new(StringBuilder::new()),
+            labels: ListBuilder::new(StructBuilder::new(
+                label_fields(),
+                vec![
+                    //TODO: This seems a bit fragile?
+                    Box::new(UInt32Builder::new()), // id
+                    Box::new(StringBuilder::new()), // cat no
+                    Box::new(StringBuilder::new()), // name
+                ],
+            )),
+            is_main_release: BooleanBuilder::new(),
+            master_id: UInt32Builder::new(),
+            tracklist: tracklist_builder(),
+            formats: formats_builder(),
+            released: StringBuilder::new(),
+            released_year: Int16Builder::new(),
+            released_date: Date32Builder::new(),
+            released_precision: StringDictionaryBuilder::<Int8Type>::new_with_dictionary(
+                3,
+                &precision_values,
+            )
+            .unwrap(),
+            identifiers: identifiers_builder(),
+            barcode: StringBuilder::new(),
+            companies: companies_builder(),
+            country: StringDictionaryBuilder::<Int16Type>::new(),
+            data_quality: data_quality_builder(),
+            notes: LargeStringBuilder::new(),
+            videos: videos_builder(),
+            current: Current::default(),
+            repeated: Repeated::new(on_duplicate),
+            duplicates: BTreeMap::new(),
+            schema,
+        }
+    }
+
+    // The "push" methods for single valued columns hold the value back for
+    // the current release, see `write_release`
+
+    fn push_id(&mut self, id: u32) -> Result<(), ProcessingError> {
+        self.repeated.fill("id", &mut self.current.id, Some(id))
+    }
+
+    fn push_status(&mut self, status: &str) -> Result<(), ProcessingError> {
+        self.repeated
+            .fill("status", &mut self.current.status, Some(status.to_owned()))
+    }
+
+    fn push_title(&mut self, title: &str) -> Result<(), ProcessingError> {
+        self.repeated
+            .fill("title", &mut self.current.title, Some(title.to_owned()))
+    }
+
+    fn push_label_id(&mut self, id: u32) {
+        //TODO: Is there a nicer way than this?
+        self.labels
+            .values()
+            .field_builder::<UInt32Builder>(0)
+            .unwrap()
+            .append_value(id);
+    }
+
+    fn push_label_cat_no(&mut self, cat_no: &str) {
+        //TODO: Is there a nicer way than this?
+        self.labels
+            .values()
+            .field_builder::<StringBuilder>(1)
+            .unwrap()
+            .append_value(cat_no);
+    }
+    fn push_label_name(&mut self, name: &str) {
+        //TODO: Is there a nicer way than this?
+        self.labels
+            .values()
+            .field_builder::<StringBuilder>(2)
+            .unwrap()
+            .append_value(name);
+    }
+
+    fn push_end_of_label(&mut self) {
+        self.labels.values().append(true);
+    }
+
+    fn push_is_main_release(&mut self, is_main: bool) -> Result<(), ProcessingError> {
+        self.repeated.fill(
+            "master_id",
+            &mut self.current.is_main_release,
+            Some(is_main),
+        )
+    }
+
+    fn push_master_id(&mut self, id: u32) -> Result<(), ProcessingError> {
+        self.repeated
+            .fill("master_id", &mut self.current.master_id, Some(id))
+    }
+
+    fn push_released(&mut self, released: Option<&str>) -> Result<(), ProcessingError> {
+        let released = released.map(str::to_owned);
+        self.repeated
+            .fill("released", &mut self.current.released, released)
+    }
+
+    fn push_barcode(&mut self, barcode: Option<&str>) -> Result<(), ProcessingError> {
+        let barcode = barcode.map(str::to_owned);
+        self.repeated
+            .fill("identifiers", &mut self.current.barcode, barcode)
+    }
+
+    fn push_country(&mut self, country: Option<&str>) -> Result<(), ProcessingError> {
+        let country = country.map(str::to_owned);
+        self.repeated
+            .fill("country", &mut self.current.country, country)
+    }
+
+    fn push_data_quality(&mut self, data_quality: Option<&str>) -> Result<(), ProcessingError> {
+        let data_quality = data_quality.map(str::to_owned);
+        self.repeated
+            .fill("data_quality", &mut self.current.data_quality, data_quality)
+    }
+
+    fn push_notes(&mut self, notes: Option<&str>) -> Result<(), ProcessingError> {
+        let notes = notes.map(str::to_owned);
+        self.repeated.fill("notes", &mut self.current.notes, notes)
+    }
+
+    fn write_release(&mut self) -> Result<(), ProcessingError> {
+        let current = std::mem::take(&mut self.current);
+
+        // There's nothing sensible to fill these in with
+        let id = current
+            .id
+            .value
+            .ok_or(ProcessingError::missing_field("id"))?;
+        let status = (current.status.value).ok_or(ProcessingError::missing_field("status"))?;
+        let title = (current.title.value).ok_or(ProcessingError::missing_field("title"))?;
+
+        // Repeated elements are reported by release, now that its id is known
+        for name in std::mem::take(&mut self.repeated.names) {
+            self.duplicates.entry(name).or_default().insert(id);
+        }
+
+        self.ids.append_value(id);
+        self.statuses.append_value(status);
+        self.titles.append_value(title);
+
+        // Everything else can be null
+        self.is_main_release
+            .append_option(current.is_main_release.value);
+        self.master_id.append_option(current.master_id.value);
+        match current.released.value {
+            Some(released) => {
+                let (year, date, precision) = parse_partial_date(&released);
+                self.released.append_value(released);
+                self.released_year.append_option(year);
+                self.released_date
+                    .append_option(date.map(Date32Type::from_naive_date));
+                match precision {
+                    Some(precision) => self.released_precision.append_value(precision),
+                    None => self.released_precision.append_null(),
+                }
+            }
+            None => {
+                self.released.append_null();
+                self.released_year.append_null();
+                self.released_date.append_null();
+                self.released_precision.append_null();
+            }
+        }
+        self.barcode.append_option(current.barcode.value);
+        match current.country.value {
+            Some(country) => self.country.append_value(country),
+            None => self.country.append_null(),
+        }
+        match current.data_quality.value {
+            Some(data_quality) => self.data_quality.append_value(data_quality),
+            None => self.data_quality.append_null(),
+        }
+        self.notes.append_option(current.notes.value);
+
+        // Mark end of current release in list builders
+        self.artists.append(true);
+        self.extra_artists.append(true);
+        self.genres.append(true);
+        self.styles.append(true);
+        self.labels.append(true);
+        self.tracklist.append(true);
+        self.formats.append(true);
+        self.identifiers.append(true);
+        self.companies.append(true);
+        self.videos.append(true);
+
+        // Checked for each release, so that a bad one can be skipped rather
+        // than taking the whole batch down with it
+        let structs = [
+            ("artists", &mut self.artists),
+            ("extra_artists", &mut self.extra_artists),
+            ("labels", &mut self.labels),
+            ("tracklist", &mut self.tracklist),
+            ("formats", &mut self.formats),
+            ("identifiers", &mut self.identifiers),
+            ("companies", &mut self.companies),
+            ("videos", &mut self.videos),
+        ];
+        for (name, list) in structs {
+            if !is_aligned(list.values()) {
+                return Err(ProcessingError::misaligned_column(name));
+            }
+        }
+
+        self.pending += 1;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Option<RecordBatch> {
+        if self.pending == 0 {
+            return None;
+        }
+        self.pending = 0;
+
+        Some(
+            RecordBatch::try_new(
+                self.schema.clone(),
+                vec![
+                    Arc::new(self.ids.finish()),
+                    Arc::new(self.statuses.finish()),
+                    Arc::new(self.titles.finish()),
+                    Arc::new(self.artists.finish()),
+                    Arc::new(self.extra_artists.finish()),
+                    Arc::new(self.genres.finish()),
+                    Arc::new(self.styles.finish()),
+                    Arc::new(self.labels.finish()),
+                    Arc::new(self.is_main_release.finish()),
+                    Arc::new(self.master_id.finish()),
+                    Arc::new(self.tracklist.finish()),
+                    Arc::new(self.formats.finish()),
+                    Arc::new(self.released.finish()),
+                    Arc::new(self.released_year.finish()),
+                    Arc::new(self.released_date.finish()),
+                    Arc::new(self.released_precision.finish()),
+                    Arc::new(self.identifiers.finish()),
+                    Arc::new(self.barcode.finish()),
+                    Arc::new(self.companies.finish()),
+                    Arc::new(self.country.finish()),
+                    Arc::new(self.data_quality.finish()),
+                    Arc::new(self.notes.finish()),
+                    Arc::new(self.videos.finish()),
+                ],
+            )
+            .unwrap(),
+        )
+    }
+
+    /// Throw away the releases that haven't been finished yet. Builders can't
+    /// be truncated, so this starts them all over.
+    fn discard_pending(&mut self) {
+        let duplicates = std::mem::take(&mut self.duplicates);
+        let on_duplicate = self.repeated.on_duplicate;
+        *self = Self::new(self.schema.clone(), on_duplicate, self.batch_size);
+        self.duplicates = duplicates;
+    }
+}
+
+/// Releases that couldn't be parsed, kept as XML so that they can be looked
+/// into, and fed back in once fixed.
+struct Quarantine {
+    path: String,
+    file: Option<BufWriter<File>>,
+    errors: BTreeMap<&'static str, usize>,
+}
+
+impl Quarantine {
+    fn new(output_file_path: &str) -> Self {
+        Quarantine {
+            path: format!("{output_file_path}.quarantine.xml"),
+            file: None,
+            errors: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, err: &ProcessingError, release: &[u8]) -> Result<(), ProcessingError> {
+        eprintln!("Skipping release: {err}");
+        *self.errors.entry(err.kind()).or_default() += 1;
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let mut file = BufWriter::new(File::create(&self.path)?);
+                file.write_all(b"<releases>\n")?;
+                self.file.insert(file)
+            }
+        };
+        file.write_all(release)?;
+        Ok(())
+    }
+
+    fn close(self) -> Result<(), ProcessingError> {
+        if let Some(mut file) = self.file {
+            file.write_all(b"</releases>\n")?;
+            file.flush()?;
+
+            let skipped: usize = self.errors.values().sum();
+            println!("Skipped {skipped} releases, see {}", self.path);
+            for (kind, count) in &self.errors {
+                println!("  {kind}: {count}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a table of releases goes, one file or a directory of them.
+enum TableWriter {
+    File(Box<dyn Sink>),
+    Partitioned(Box<PartitionedWriter>),
+}
+
+impl TableWriter {
+    fn write(&mut self, partition: &str, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        match self {
+            TableWriter::File(writer) => writer.write(batch),
+            TableWriter::Partitioned(writer) => writer.write(partition, batch),
+        }
+    }
+
+    fn close(self) -> Result<(), ProcessingError> {
+        match self {
+            TableWriter::File(writer) => writer.close(),
+            TableWriter::Partitioned(writer) => writer.close(),
+        }
+    }
+}
+
+/// What a worker made of a chunk of releases.
+struct ParsedReleases {
+    records: usize,
+    /// For each partition the releases fell in, a batch for each table of the
+    /// layout
+    batches: Vec<(String, Vec<RecordBatch>)>,
+    skipped: Vec<(ProcessingError, Vec<u8>)>,
+    duplicates: BTreeMap<&'static str, BTreeSet<u32>>,
+}
+
+pub fn write_releases(
+    reader: &mut EventReader,
+    output_file_path: &str,
+    on_error: OnError,
+    on_duplicate: OnDuplicate,
+    threads: usize,
+    options: &OutputOptions,
+) -> Result<(), ProcessingError> {
+    let schema = Arc::new(release_schema());
+
+    // Split output is a directory of parts for each table, and the normalized
+    // layout puts the file or directory for each table in the output directory,
+    // or for SQLite, each table in the one database
+    let is_split = options.partition_by.is_some() || options.max_file_size.is_some();
+    let is_database = options.format == Format::Sqlite;
+    let tables = match options.layout {
+        Layout::Nested => vec![("releases", PathBuf::from(output_file_path), schema.clone())],
+        Layout::Normalized => (normalize::tables().into_iter())
+            .map(|(name, schema)| {
+                let path = if is_database {
+                    PathBuf::from(output_file_path)
+                } else if is_split {
+                    Path::new(output_file_path).join(name)
+                } else {
+                    let extension = options.format.extension();
+                    Path::new(output_file_path).join(format!("{name}.{extension}"))
+                };
+                (name, path, schema)
+            })
+            .collect(),
+    };
+    if is_split || (options.layout == Layout::Normalized && !is_database) {
+        fs::create_dir_all(output_file_path)?;
+    }
+
+    let mut writers = Vec::new();
+    for (name, path, schema) in tables {
+        let schema = partition::strip_schema(&schema, options.partition_by)?;
+        let writer = if is_split {
+            TableWriter::Partitioned(Box::new(PartitionedWriter::new(path, schema, options)))
+        } else {
+            TableWriter::File(sink::open(&path, name, schema, options)?)
+        };
+        writers.push(writer);
+    }
+
+    let mut quarantine = Quarantine::new(output_file_path);
+    let mut duplicates: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+    let mut n: usize = 0;
+
+    pipeline::run(
+        reader,
+        "releases",
+        "release",
+        threads,
+        options.batch_size,
+        |chunk| parse_chunk(chunk, &schema, on_error, on_duplicate, options),
+        |parsed| {
+            for (partition, batches) in parsed.batches {
+                for (writer, batch) in writers.iter_mut().zip(batches) {
+                    writer.write(&partition, &batch)?;
+                }
+            }
+            for (err, release) in parsed.skipped {
+                quarantine.add(&err, &release)?;
+            }
+            for (name, ids) in parsed.duplicates {
+                duplicates.entry(name).or_default().extend(ids);
+            }
+
+            // Chunks don't have to end on a multiple of 10,000
+            let before = n;
+            n += parsed.records;
+            if n / 10_000 != before / 10_000 {
+                println!("{n}");
+            }
+            Ok(())
+        },
+    )?;
+
+    for (name, ids) in &duplicates {
+        let ids: Vec<_> = ids.iter().map(u32::to_string).collect();
+        println!(
+            "Repeated <{name}> in {} releases: {}",
+            ids.len(),
+            ids.join(", ")
+        );
+    }
+    for writer in writers {
+        writer.close()?;
+    }
+    quarantine.close()?;
+
+    Ok(())
+}
+
+fn parse_chunk(
+    chunk: Chunk,
+    schema: &Arc<Schema>,
+    on_error: OnError,
+    on_duplicate: OnDuplicate,
+    options: &OutputOptions,
+) -> Result<ParsedReleases, ProcessingError> {
+    let records = chunk.records;
+    let mut reader = chunk.reader("releases")?;
+    let mut writer = ReleaseBatchWriter::new(schema.clone(), on_duplicate, options.batch_size);
+    let mut skipped = Vec::new();
+
+    // The reader stops where the error was found, so it knows where that was
+    parse_releases(&mut reader, &mut writer, on_error, &mut skipped)
+        .map_err(|err| err.locate(&reader))?;
+
+    // Splitting into partitions and tables is done here too, to share it
+    // between the workers
+    let partitions = match (writer.finish(), options.partition_by) {
+        (None, _) => Vec::new(),
+        (Some(batch), None) => vec![(String::new(), batch)],
+        (Some(batch), Some(by)) => partition::split(&batch, by)?,
+    };
+    let batches = (partitions.into_iter())
+        .map(|(partition, batch)| {
+            let tables = match options.layout {
+                Layout::Nested => vec![batch],
+                Layout::Normalized => normalize::normalize(&batch)?,
+            };
+            let tables = (tables.into_iter())
+                .map(|batch| partition::strip(batch, options.partition_by))
+                .collect::<Result<_, _>>()?;
+            Ok((partition, tables))
+        })
+        .collect::<Result<_, ProcessingError>>()?;
+
+    Ok(ParsedReleases {
+        records,
+        batches,
+        skipped,
+        duplicates: std::mem::take(&mut writer.duplicates),
+    })
+}
+
+/// Parse releases, one per line, into a batch, for tests that need them nested.
+#[cfg(test)]
+pub fn parse_batch(releases: &[&str]) -> RecordBatch {
+    let xml: String = releases
+        .iter()
+        .map(|release| format!("{release}\n"))
+        .collect();
+    let chunk = Chunk {
+        offset: 0,
+        xml: format!("<releases>{xml}").into_bytes(),
+        records: releases.len(),
+    };
+    let mut reader = chunk.reader("releases").unwrap();
+    let schema = Arc::new(release_schema());
+    let mut writer = ReleaseBatchWriter::new(schema, OnDuplicate::Error, releases.len());
+    parse_releases(&mut reader, &mut writer, OnError::Fail, &mut Vec::new()).unwrap();
+    writer.finish().unwrap()
+}
+
+fn parse_releases(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+    on_error: OnError,
+    skipped: &mut Vec<(ProcessingError, Vec<u8>)>,
+) -> Result<(), ProcessingError> {
+    // To skip a release we need its XML, and the XML of the releases before
+    // it to rebuild the batch without it
+    if on_error == OnError::Skip {
+        reader.start_recording();
+    }
+
+    loop {
+        reader.set_record_id(None);
+        let release_offset = reader.recording().len();
+        let event = reader.advance()?;
+
+        if matches!(event, Event::Eof) {
+            break Ok(());
+        }
+
+        // Owned, so that the attributes can set the id of the release on the reader
+        let release_start = event.expect_start_of("release")?.into_owned();
+
+        let parsed = parse_release_attributes(reader, &release_start, writer)
+            .and_then(|()| parse_release(reader, writer))
+            .and_then(|()| writer.write_release());
+
+        match parsed {
+            Ok(()) => {}
+            Err(err) if on_error == OnError::Skip && err.is_recoverable() => {
+                let err = err.locate(reader);
+                let release = skip_release(reader, writer, release_offset)?;
+                skipped.push((err, release));
+            }
+            Err(err) => break Err(err),
+        }
+    }
+}
+
+/// Take out the release that starts at `release_offset` in the recording,
+/// and rebuild the batch without it.
+fn skip_release(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+    release_offset: usize,
+) -> Result<Vec<u8>, ProcessingError> {
+    // Read on to the end of the release, to carry on from the next one
+    if reader.depth() > 1 {
+        while reader.depth() > 1 {
+            reader.advance()?;
+        }
+        reader.advance()?.expect_new_line()?;
+    }
+
+    let release = reader.recording().split_off(release_offset);
+
+    // Some of the release has already gone into the builders, which can't be
+    // rolled back, so replay the releases before it into new ones
+    writer.discard_pending();
+    let mut replay = EventReader::from_reader(Cursor::new(reader.recording().clone()));
+    loop {
+        let event = replay.advance()?;
+
+        if matches!(event, Event::Eof) {
+            break;
+        }
+
+        let release_start = event.expect_start_of("release")?.into_owned();
+        parse_release_attributes(&mut replay, &release_start, writer)?;
+        parse_release(&mut replay, writer)?;
+        writer.write_release()?;
+    }
+
+    Ok(release)
+}
+
+/// Sets the id of the release on the reader as soon as it is known, so that
+/// errors in the rest of the release, and its other attributes, can refer to it.
+fn parse_release_attributes(
+    reader: &mut EventReader,
+    release_start: &BytesStart,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    for a in release_start.attributes() {
+        match a {
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"id"), ..
+                },
+            ) => {
+                let id = integer(&unescape_value(&attribute)?)?;
+                reader.set_record_id(Some(id));
+                writer.push_id(id)?;
+            }
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"status"),
+                    ..
+                },
+            ) => {
+                let status = unescape_value(&attribute)?;
+                writer.push_status(&status)?;
+            }
+            Ok(Attribute { key, .. }) => {
+                return Err(ProcessingError::unknown_attribute(key.into_inner()))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+fn parse_release(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("release") {
+            break;
+        }
+
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        let event = event.expect_start()?;
+
+        // We can't assume the order of elements within a release
+        match event.name().into_inner() {
+            b"title" => parse_title(reader, writer)?,
+            b"genres" => parse_genres(reader, &mut writer.genres)?,
+            b"styles" => parse_styles(reader, &mut writer.styles)?,
+            b"images" => parse_images(reader)?,
+            b"artists" => parse_artists(reader, &mut writer.artists, &mut writer.repeated)?,
+            b"extraartists" => {
+                parse_extra_artists(reader, &mut writer.extra_artists, &mut writer.repeated)?
+            }
+            b"labels" => parse_labels(reader, writer)?,
+            b"formats" => parse_formats(reader, writer)?,
+            b"country" => parse_country(reader, writer)?,
+            b"data_quality" => parse_data_quality(reader, writer)?,
+            b"tracklist" => parse_tracklist(reader, writer)?,
+            b"videos" => parse_videos(reader, &mut writer.videos, &mut writer.repeated)?,
+            b"released" => parse_released(reader, writer)?,
+            b"companies" => parse_companies(reader, writer)?,
+            b"notes" => parse_notes(reader, writer)?,
+            b"identifiers" => parse_identifiers(reader, writer)?,
+            b"master_id" => {
+                parse_master_id_attributes(&event, writer)?;
+                parse_master_id(reader, writer)?;
+            }
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+    reader.advance()?.expect_new_line()?;
+
+    Ok(())
+}
+
+fn parse_title(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    let title = reader.advance()?.expect_text()?;
+    let title = unescape(&title)?;
+    writer.push_title(&title)?;
+
+    reader.advance()?.expect_end_of("title")?;
+
+    Ok(())
+}
+
+fn parse_labels(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("labels") {
+            break Ok(());
+        }
+
+        let label = event.expect_empty("label")?;
+
+        let mut has_id = false;
+        let mut has_cat_no = false;
+        let mut has_name = false;
+
+        for a in label.attributes() {
+            match a {
+                Ok(
+                    attribute @ Attribute {
+                        key: QName(b"id"), ..
+                    },
+                ) => {
+                    let id = integer(&unescape_value(&attribute)?)?;
+                    writer.push_label_id(id);
+                    has_id = true;
+                }
+                Ok(
+                    attribute @ Attribute {
+                        key: QName(b"catno"),
+                        ..
+                    },
+                ) => {
+                    let cat_no = unescape_value(&attribute)?;
+                    writer.push_label_cat_no(&cat_no);
+                    has_cat_no = true;
+                }
+                Ok(
+                    attribute @ Attribute {
+                        key: QName(b"name"),
+                        ..
+                    },
+                ) => {
+                    let name = unescape_value(&attribute)?;
+                    writer.push_label_name(&name);
+                    has_name = true;
+                }
+                Ok(Attribute { key, .. }) => {
+                    return Err(ProcessingError::unknown_attribute(key.into_inner()))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // None of these can be null, so there's nothing to fill them in with
+        if !has_id {
+            return Err(ProcessingError::missing_field("id"));
+        }
+        if !has_cat_no {
+            return Err(ProcessingError::missing_field("catno"));
+        }
+        if !has_name {
+            return Err(ProcessingError::missing_field("name"));
+        }
+
+        writer.push_end_of_label();
+    }
+}
+
+fn parse_formats(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("formats") {
+            break Ok(());
+        }
+
+        // A format without descriptions is written as an empty tag
+        let is_empty = event.is_empty_tag();
+        let format = if is_empty {
+            event.expect_empty("format")?
+        } else {
+            event.expect_start_of("format")?
+        };
+
+        let format_builder = writer.formats.values();
+        parse_format_attributes(&format, format_builder)?;
+
+        let descriptions =
+            struct_field::<ListBuilder<StringBuilder>>(format_builder, FORMAT_DESCRIPTIONS);
+        if !is_empty {
+            parse_format(reader, descriptions)?;
+        }
+        descriptions.append(true);
+
+        format_builder.append(true);
+    }
+}
+
+fn parse_format_attributes(
+    format_start: &BytesStart,
+    format: &mut StructBuilder,
+) -> Result<(), ProcessingError> {
+    let mut has_name = false;
+    let mut has_qty = false;
+    let mut has_text = false;
+
+    for a in format_start.attributes() {
+        match a {
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"name"),
+                    ..
+                },
+            ) => {
+                let name = unescape_value(&attribute)?;
+                struct_field::<StringBuilder>(format, FORMAT_NAME).append_value(name);
+                has_name = true;
+            }
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"qty"), ..
+                },
+            ) => {
+                // Quantity is occasionally left blank
+                let qty = match unescape_value(&attribute)?.as_ref() {
+                    "" => None,
+                    qty => Some(integer(qty)?),
+                };
+                struct_field::<UInt32Builder>(format, FORMAT_QTY).append_option(qty);
+                has_qty = true;
+            }
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"text"),
+                    ..
+                },
+            ) => {
+                let text = unescape_value(&attribute)?;
+                let text = Some(text).filter(|text| !text.is_empty());
+                struct_field::<StringBuilder>(format, FORMAT_TEXT).append_option(text);
+                has_text = true;
+            }
+            Ok(Attribute { key, .. }) => {
+                return Err(ProcessingError::unknown_attribute(key.into_inner()))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    if !has_name {
+        struct_field::<StringBuilder>(format, FORMAT_NAME).append_null();
+    }
+    if !has_qty {
+        struct_field::<UInt32Builder>(format, FORMAT_QTY).append_null();
+    }
+    if !has_text {
+        struct_field::<StringBuilder>(format, FORMAT_TEXT).append_null();
+    }
+
+    Ok(())
+}
+
+fn parse_format(
+    reader: &mut EventReader,
+    descriptions: &mut ListBuilder<StringBuilder>,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("format") {
+            break Ok(());
+        }
+
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        event.expect_start_of("descriptions")?;
+
+        loop {
+            let event = reader.advance()?;
+
+            if event.is_end_of("descriptions") {
+                break;
+            }
+
+            event.expect_start_of("description")?;
+
+            let description = reader
+                .read_text("description")?
+                .ok_or(ProcessingError::expected_text())?;
+            descriptions.values().append_value(description);
+        }
+    }
+}
+
+fn parse_country(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    let country = reader.read_text("country")?;
+    writer.push_country(country)
+}
+
+fn parse_data_quality(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    let data_quality = reader.read_text("data_quality")?;
+    writer.push_data_quality(data_quality)
+}
+
+fn parse_master_id_attributes(
+    master_id_start: &BytesStart,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    for a in master_id_start.attributes() {
+        match a {
+            Ok(
+                attribute @ Attribute {
+                    key: QName(b"is_main_release"),
+                    ..
+                },
+            ) => {
+                let is_main = match unescape_value(&attribute)?.as_ref() {
+                    "true" => true,
+                    "false" => false,
+                    is_main => return Err(ProcessingError::invalid_boolean(is_main.as_bytes())),
+                };
+                writer.push_is_main_release(is_main)?;
+            }
+            Ok(Attribute { key, .. }) => {
+                return Err(ProcessingError::unknown_attribute(key.into_inner()))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_master_id(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    let master_id = reader.advance()?.expect_text()?;
+    let master_id = integer(&unescape(&master_id)?)?;
+    writer.push_master_id(master_id)?;
+
+    reader.advance()?.expect_end_of("master_id")?;
+    Ok(())
+}
+
+fn parse_tracklist(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    parse_tracks(
+        reader,
+        "tracklist",
+        &mut writer.tracklist,
+        &mut writer.repeated,
+    )
+}
+
+fn parse_tracks(
+    reader: &mut EventReader,
+    end_of: &'static str,
+    tracks: &mut ListBuilder<StructBuilder>,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of(end_of) {
+            break Ok(());
+        }
+
+        event.expect_start_of("track")?;
+
+        parse_track(reader, tracks.values(), repeated)?;
+    }
+}
+
+fn parse_track(
+    reader: &mut EventReader,
+    track: &mut StructBuilder,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    let is_top_level = track.num_fields() > TRACK_SUB_TRACKS;
+    let mut position = Single::default();
+    let mut type_ = Single::default();
+    let mut title = Single::default();
+    let mut duration = Single::default();
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("track") {
+            break;
+        }
+
+        // Empty fields are filled in with nulls below
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        let event = event.expect_start()?;
+
+        match event.name().into_inner() {
+            b"position" => {
+                let value = reader.read_text("position")?.map(str::to_owned);
+                repeated.fill("track/position", &mut position, value)?;
+            }
+            b"type_" => {
+                let value = reader.read_text("type_")?.map(str::to_owned);
+                repeated.fill("track/type_", &mut type_, value)?;
+            }
+            b"title" => {
+                let value = reader.read_text("title")?.map(str::to_owned);
+                repeated.fill("track/title", &mut title, value)?;
+            }
+            b"duration" => {
+                let value = reader.read_text("duration")?.map(str::to_owned);
+                repeated.fill("track/duration", &mut duration, value)?;
+            }
+            b"artists" => parse_artists(
+                reader,
+                struct_field::<ListBuilder<StructBuilder>>(track, TRACK_ARTISTS),
+                repeated,
+            )?,
+            b"extraartists" => parse_extra_artists(
+                reader,
+                struct_field::<ListBuilder<StructBuilder>>(track, TRACK_EXTRA_ARTISTS),
+                repeated,
+            )?,
+            b"sub_tracks" => {
+                // Sub tracks don't nest any further, so only a top level
+                // track has somewhere to put them
+                if !is_top_level {
+                    return Err(ProcessingError::expected_end_of("track"));
+                }
+                parse_tracks(
+                    reader,
+                    "sub_tracks",
+                    struct_field::<ListBuilder<StructBuilder>>(track, TRACK_SUB_TRACKS),
+                    repeated,
+                )?;
+            }
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+
+    struct_field::<StringBuilder>(track, TRACK_POSITION).append_option(position.value);
+    struct_field::<StringBuilder>(track, TRACK_TYPE).append_option(type_.value);
+    struct_field::<StringBuilder>(track, TRACK_TITLE).append_option(title.value);
+    struct_field::<StringBuilder>(track, TRACK_DURATION).append_option(duration.value);
+
+    // Close off this track's entry in each of its list fields
+    struct_field::<ListBuilder<StructBuilder>>(track, TRACK_ARTISTS).append(true);
+    struct_field::<ListBuilder<StructBuilder>>(track, TRACK_EXTRA_ARTISTS).append(true);
+    if is_top_level {
+        struct_field::<ListBuilder<StructBuilder>>(track, TRACK_SUB_TRACKS).append(true);
+    }
+
+    track.append(true);
+
+    Ok(())
+}
+
+fn parse_released(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    let released = reader.read_text("released")?;
+    writer.push_released(released)
+}
+
+/// Release dates come as `YYYY`, `YYYY-MM` or `YYYY-MM-DD`, with `00` standing
+/// in for an unknown month or day. Returns the year, the date if it is fully
+/// known, and how precisely the date was given.
+fn parse_partial_date(released: &str) -> (Option<i16>, Option<NaiveDate>, Option<&'static str>) {
+    let mut parts = released.trim().split('-');
+
+    let year = parts
+        .next()
+        .filter(|year| year.len() == 4 && year.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|year| year.parse::<i16>().ok())
+        .filter(|year| *year > 0);
+
+    let Some(year) = year else {
+        return (None, None, None);
+    };
+
+    let month = match parts.next().map(str::parse::<u32>) {
+        Some(Ok(month @ 1..=12)) => month,
+        _ => return (Some(year), None, Some("year")),
+    };
+
+    let day = match parts.next().map(str::parse::<u32>) {
+        Some(Ok(day)) if day > 0 => day,
+        _ => return (Some(year), None, Some("month")),
+    };
+
+    match NaiveDate::from_ymd_opt(year.into(), month, day) {
+        Some(date) => (Some(year), Some(date), Some("day")),
+        None => (Some(year), None, Some("month")),
+    }
+}
+
+fn parse_companies(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("companies") {
+            break Ok(());
+        }
+
+        event.expect_start_of("company")?;
+
+        parse_company(reader, writer.companies.values(), &mut writer.repeated)?;
+    }
+}
+
+fn parse_company(
+    reader: &mut EventReader,
+    company: &mut StructBuilder,
+    repeated: &mut Repeated,
+) -> Result<(), ProcessingError> {
+    let mut id = Single::default();
+    let mut name = Single::default();
+    let mut catno = Single::default();
+    let mut entity_type = Single::default();
+    let mut entity_type_name = Single::default();
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("company") {
+            break;
+        }
+
+        if event.is_empty_tag() {
+            continue;
+        }
+
+        let event = event.expect_start()?;
+
+        match event.name().into_inner() {
+            b"id" => {
+                let value = reader.read_text("id")?.map(integer).transpose()?;
+                repeated.fill("company/id", &mut id, value)?;
+            }
+            b"name" => {
+                let value = reader.read_text("name")?.map(str::to_owned);
+                repeated.fill("company/name", &mut name, value)?;
+            }
+            b"catno" => {
+                let value = reader.read_text("catno")?.map(str::to_owned);
+                repeated.fill("company/catno", &mut catno, value)?;
+            }
+            b"entity_type" => {
+                let value = reader.read_text("entity_type")?.map(integer).transpose()?;
+                repeated.fill("company/entity_type", &mut entity_type, value)?;
+            }
+            b"entity_type_name" => {
+                let value = reader.read_text("entity_type_name")?.map(str::to_owned);
+                repeated.fill("company/entity_type_name", &mut entity_type_name, value)?;
+            }
+            b"resource_url" => {
+                // Resource urls can be derived from the id, so we can skip
+                reader.skip_to_end_of("resource_url")?;
+            }
+            _ => return Err(ProcessingError::unknown_element(event.name().into_inner())),
+        }
+    }
+
+    struct_field::<UInt32Builder>(company, COMPANY_ID).append_option(id.value);
+    struct_field::<StringBuilder>(company, COMPANY_NAME).append_option(name.value);
+    struct_field::<StringBuilder>(company, COMPANY_CATNO).append_option(catno.value);
+    struct_field::<UInt32Builder>(company, COMPANY_ENTITY_TYPE).append_option(entity_type.value);
+    struct_field::<StringBuilder>(company, COMPANY_ENTITY_TYPE_NAME)
+        .append_option(entity_type_name.value);
+    company.append(true);
+
+    Ok(())
+}
+
+fn parse_notes(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    let notes = reader.read_text("notes")?;
+    writer.push_notes(notes)
+}
+
+fn parse_identifiers(
+    reader: &mut EventReader,
+    writer: &mut ReleaseBatchWriter,
+) -> Result<(), ProcessingError> {
+    // The first barcode that turns out to be a valid EAN/UPC
+    let mut barcode = None;
+
+    loop {
+        let event = reader.advance()?;
+
+        if event.is_end_of("identifiers") {
+            break;
+        }
+
+        let identifier_start = event.expect_empty("identifier")?;
+        let identifier = writer.identifiers.values();
+
+        let mut type_ = None;
+        let mut description = None;
+        let mut value = None;
+
+        for a in identifier_start.attributes() {
+            match a {
+                Ok(
+                    attribute @ Attribute {
+                        key: QName(b"type"),
+                        ..
+                    },
+                ) => type_ = Some(unescape_value(&attribute)?),
+                Ok(
+                    attribute @ Attribute {
+                        key: QName(b"description"),
+                        ..
+                    },
+                ) => description = Some(unescape_value(&attribute)?),
+                Ok(
+                    attribute @ Attribute {
+                        key: QName(b"value"),
+                        ..
+                    },
+                ) => value = Some(unescape_value(&attribute)?),
+                Ok(Attribute { key, .. }) => {
+                    return Err(ProcessingError::unknown_attribute(key.into_inner()))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let type_ = type_.as_deref();
+        let description = description.as_deref();
+        let value = value.as_deref();
+
+        if barcode.is_none() && type_ == Some("Barcode") {
+            barcode = value.and_then(normalize_barcode);
+        }
+
+        struct_field::<StringBuilder>(identifier, IDENTIFIER_TYPE).append_option(type_);
+        struct_field::<StringBuilder>(identifier, IDENTIFIER_DESCRIPTION)
+            .append_option(description);
+        struct_field::<StringBuilder>(identifier, IDENTIFIER_VALUE).append_option(value);
+        identifier.append(true);
+    }
+
+    writer.push_barcode(barcode.as_deref())?;
+
+    Ok(())
+}
+
+/// Strip a barcode as entered on Discogs down to its digits, keeping it only
+/// if it forms an EAN-8, UPC-A or EAN-13 with a valid check digit.
+fn normalize_barcode(barcode: &str) -> Option<String> {
+    let digits: String = barcode.chars().filter(char::is_ascii_digit).collect();
+
+    if ![8, 12, 13].contains(&digits.len()) {
+        return None;
+    }
+
+    let mut digits_rev = digits.bytes().rev().map(|b| u32::from(b - b'0'));
+    let check_digit = digits_rev.next()?;
+
+    // Weights alternate 3, 1, 3... working leftwards from the check digit
+    let sum: u32 = digits_rev
+        .zip([3, 1].into_iter().cycle())
+        .map(|(digit, weight)| digit * weight)
+        .sum();
+
+    if (10 - sum % 10) % 10 == check_digit {
+        Some(digits)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow::array::{AsArray, UInt32Array};
+    use arrow::datatypes::UInt32Type;
+
+    const GOOD: &str = r#"<release id="1" status="Accepted"><title>Good</title><artists><artist><id>10</id><name>A</name></artist></artists></release>
+"#;
+    // Without an id, the artist's fields would come out one value short
+    const NESTED_ARTIST: &str = r#"<release id="2" status="Accepted"><title>Bad</title><artists><artist><name>B</name></artist></artists></release>
+"#;
+    // Two titles would be one value too many
+    const REPEATED_TRACK_TITLE: &str = r#"<release id="3" status="Accepted"><title>Bad</title><tracklist><track><title>One</title><title>Two</title></track></tracklist></release>
+"#;
+    const ALSO_GOOD: &str = r#"<release id="4" status="Accepted"><title>Good</title><tracklist><track><position>A</position><title>One</title></track></tracklist></release>
+"#;
+
+    #[test]
+    fn malformed_nested_structs_are_quarantined() {
+        let xml = [
+            "<releases>",
+            GOOD,
+            NESTED_ARTIST,
+            REPEATED_TRACK_TITLE,
+            ALSO_GOOD,
+        ]
+        .concat();
+        let chunk = Chunk {
+            offset: 0,
+            xml: xml.into_bytes(),
+            records: 4,
+        };
+        let mut reader = chunk.reader("releases").unwrap();
+        let schema = Arc::new(release_schema());
+        let mut writer = ReleaseBatchWriter::new(schema, OnDuplicate::Error, 10);
+        let mut skipped = Vec::new();
+
+        parse_releases(&mut reader, &mut writer, OnError::Skip, &mut skipped).unwrap();
+
+        let kinds: Vec<_> = skipped.iter().map(|(err, _)| err.kind()).collect();
+        assert_eq!(kinds, ["MissingField", "DuplicateElement"]);
+
+        // The rest make a batch that can be finished
+        let batch = writer.finish().unwrap();
+        let ids = batch.column(0).as_primitive::<UInt32Type>();
+        assert_eq!(ids, &UInt32Array::from(vec![1, 4]));
+        let tracklist = batch.column_by_name("tracklist").unwrap().as_list::<i32>();
+        assert_eq!(tracklist.value(1).len(), 1);
+
+        let output = std::env::temp_dir().join(format!("quarantine-{}", std::process::id()));
+        let output = output.to_str().unwrap();
+        let mut quarantine = Quarantine::new(output);
+        for (err, release) in &skipped {
+            quarantine.add(err, release).unwrap();
+        }
+        let path = quarantine.path.clone();
+        quarantine.close().unwrap();
+
+        let quarantined = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected = [
+            "<releases>\n",
+            NESTED_ARTIST,
+            REPEATED_TRACK_TITLE,
+            "</releases>\n",
+        ];
+        assert_eq!(quarantined, expected.concat());
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(year, month, day)
+    }
+
+    #[test]
+    fn partial_dates_by_precision() {
+        let day = (Some(1999), date(1999, 2, 28), Some("day"));
+        assert_eq!(parse_partial_date("1999-02-28"), day);
+        assert_eq!(parse_partial_date(" 1999-02-28\n"), day);
+
+        let month = (Some(1999), None, Some("month"));
+        assert_eq!(parse_partial_date("1999-02"), month);
+        assert_eq!(parse_partial_date("1999-02-00"), month);
+
+        let year = (Some(1999), None, Some("year"));
+        assert_eq!(parse_partial_date("1999"), year);
+        assert_eq!(parse_partial_date("1999-00"), year);
+        assert_eq!(parse_partial_date("1999-00-00"), year);
+    }
+
+    #[test]
+    fn partial_dates_keep_what_is_valid() {
+        // Day that isn't in the month
+        let month = (Some(1999), None, Some("month"));
+        assert_eq!(parse_partial_date("1999-02-30"), month);
+        assert_eq!(parse_partial_date("1999-04-31"), month);
+        assert_eq!(parse_partial_date("1999-02-xx"), month);
+
+        // Leap years
+        let leap = (Some(2000), date(2000, 2, 29), Some("day"));
+        assert_eq!(parse_partial_date("2000-02-29"), leap);
+        let not_leap = (Some(1900), None, Some("month"));
+        assert_eq!(parse_partial_date("1900-02-29"), not_leap);
+
+        // Month out of range
+        let year = (Some(1999), None, Some("year"));
+        assert_eq!(parse_partial_date("1999-13-01"), year);
+        assert_eq!(parse_partial_date("1999-?"), year);
+    }
+
+    #[test]
+    fn partial_dates_without_a_year() {
+        let invalid = ["", "0000", "0000-01-01", "99", "19999", "abcd", "-1999"];
+        for released in invalid {
+            let parsed = parse_partial_date(released);
+            assert_eq!(parsed, (None, None, None), "{released:?}");
+        }
+    }
+
+    fn barcode(digits: &str) -> Option<String> {
+        Some(digits.to_owned())
+    }
+
+    #[test]
+    fn valid_barcodes() {
+        assert_eq!(normalize_barcode("4006381333931"), barcode("4006381333931"));
+        assert_eq!(normalize_barcode("036000291452"), barcode("036000291452"));
+        assert_eq!(normalize_barcode("73513537"), barcode("73513537"));
+        // A check digit of 0
+        assert_eq!(normalize_barcode("5012345678900"), barcode("5012345678900"));
+    }
+
+    #[test]
+    fn barcodes_with_a_bad_check_digit() {
+        assert_eq!(normalize_barcode("4006381333932"), None);
+        assert_eq!(normalize_barcode("036000291453"), None);
+        assert_eq!(normalize_barcode("73513538"), None);
+    }
+
+    #[test]
+    fn barcodes_with_separators() {
+        assert_eq!(
+            normalize_barcode("4 006381 333931"),
+            barcode("4006381333931")
+        );
+        assert_eq!(
+            normalize_barcode("0-36000-29145-2"),
+            barcode("036000291452")
+        );
+        assert_eq!(normalize_barcode(" 7351 3537 "), barcode("73513537"));
+        assert_eq!(
+            normalize_barcode("Barcode: 036000291452"),
+            barcode("036000291452")
+        );
+        // Still checked once the separators are gone
+        assert_eq!(normalize_barcode("0 36000 29145 3"), None);
+    }
+
+    #[test]
+    fn barcodes_of_the_wrong_length() {
+        // 0 to 7, 9 to 11 and 14 digits
+        let mut codes = vec![String::new(), "-".to_owned()];
+        codes.extend([1, 7, 9, 10, 11, 14].map(|len| "0".repeat(len)));
+        for code in codes {
+            assert_eq!(normalize_barcode(&code), None, "{code:?}");
+        }
+    }
+
+    #[test]
+    fn misaligned_structs_are_caught() {
+        let mut artists = artists_builder();
+        assert!(is_aligned(artists.values()));
+
+        // An artist with only an id
+        struct_field::<UInt32Builder>(artists.values(), 0).append_value(1);
+        artists.values().append(true);
+        assert!(!is_aligned(artists.values()));
+    }
+}
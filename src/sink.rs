@@ -0,0 +1,531 @@
+//! Where batches of records end up. Parquet is the default, and Arrow IPC is
+//! there for readers that want to memory map the batches as they are, as a
+//! file (also known as Feather) or as a stream. Newline delimited JSON keeps
+//! the nesting of the Arrow schema, and CSV flattens it, see `flatten`.
+//! SQLite gets a table in a database rather than a file of its own, with the
+//! lists and structs in it written as JSON. DuckDB can attach the database as
+//! it is, with its sqlite extension.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, AsArray, BooleanArray, ListArray, StringBuilder};
+use arrow::buffer::NullBuffer;
+use arrow::compute::{cast, nullif};
+use arrow::csv;
+use arrow::datatypes::{DataType, Field, Int64Type, Schema};
+use arrow::error::ArrowError;
+use arrow::ipc::writer::{FileWriter, StreamWriter};
+use arrow::json::writer::{array_to_json_array, record_batches_to_json_rows};
+use arrow::json::LineDelimitedWriter;
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+
+use serde_json::Value as JsonValue;
+
+use parquet::arrow::ArrowWriter;
+
+use crate::error::ProcessingError;
+use crate::normalize;
+use crate::output::{Format, Layout, OutputOptions, SCHEMA_VERSION};
+
+pub trait Sink {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ProcessingError>;
+
+    /// Roughly how many bytes are held in memory, waiting to be written.
+    fn buffered(&self) -> usize {
+        0
+    }
+
+    /// Write out whatever is held in memory.
+    fn flush(&mut self) -> Result<(), ProcessingError> {
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ProcessingError>;
+}
+
+/// Start writing a table to the file at `path`, or for SQLite, to a table
+/// named `table` in the database at `path`, in place of any already there.
+pub fn open(
+    path: &Path,
+    table: &str,
+    schema: Arc<Schema>,
+    options: &OutputOptions,
+) -> Result<Box<dyn Sink>, ProcessingError> {
+    match options.format {
+        Format::Sqlite => Ok(Box::new(SqliteSink::open(path, table, &schema, options)?)),
+        _ => create(File::create(path)?, schema, options),
+    }
+}
+
+/// Start writing batches with the given schema, in the format asked for.
+pub fn create<W: Write + Send + 'static>(
+    file: W,
+    schema: Arc<Schema>,
+    options: &OutputOptions,
+) -> Result<Box<dyn Sink>, ProcessingError> {
+    let sink: Box<dyn Sink> = match options.format {
+        Format::Parquet => {
+            let properties = options.writer_properties();
+            let writer = ArrowWriter::try_new(file, schema, Some(properties))?;
+            Box::new(ParquetSink(writer))
+        }
+        Format::Ipc => {
+            // An IPC file can only have one dictionary for each column, where
+            // each batch has its own, so the values are written out in full
+            let fields: Vec<_> = (schema.fields().iter())
+                .map(|field| match field.data_type() {
+                    DataType::Dictionary(_, values) => {
+                        Field::new(field.name(), values.as_ref().clone(), field.is_nullable())
+                    }
+                    _ => field.as_ref().clone(),
+                })
+                .collect();
+            let schema = Arc::new(with_version(Schema::new(fields)));
+            let writer = FileWriter::try_new(file, &schema)?;
+            Box::new(IpcFileSink { writer, schema })
+        }
+        Format::IpcStream => {
+            // A stream tells its dictionaries apart by id, which is the same
+            // for every field unless given, so each needs one of its own
+            let fields: Vec<_> = (schema.fields().iter().enumerate())
+                .map(|(i, field)| match field.data_type() {
+                    DataType::Dictionary(_, _) => Field::new_dict(
+                        field.name(),
+                        field.data_type().clone(),
+                        field.is_nullable(),
+                        i as i64,
+                        false,
+                    ),
+                    _ => field.as_ref().clone(),
+                })
+                .collect();
+            let schema = Arc::new(with_version(Schema::new(fields)));
+            let writer = StreamWriter::try_new(file, &schema)?;
+            Box::new(IpcStreamSink { writer, schema })
+        }
+        Format::Json => Box::new(JsonSink(LineDelimitedWriter::new(file))),
+        Format::Csv => {
+            let mut sink = CsvSink {
+                writer: csv::Writer::new(file),
+                separator: options.list_separator.clone(),
+            };
+            // The header goes in even if there are no records
+            sink.write(&RecordBatch::new_empty(schema))?;
+            Box::new(sink)
+        }
+        Format::Sqlite => unreachable!("SQLite tables are written to a database, see `open`"),
+    };
+    Ok(sink)
+}
+
+/// Parquet has the schema version in the footer, IPC in the schema.
+fn with_version(schema: Schema) -> Schema {
+    let version = (
+        "discogs_schema_version".to_owned(),
+        SCHEMA_VERSION.to_string(),
+    );
+    schema.with_metadata(HashMap::from([version]))
+}
+
+struct ParquetSink<W: Write + Send>(ArrowWriter<W>);
+
+impl<W: Write + Send> Sink for ParquetSink<W> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        self.0.write(batch)?;
+        Ok(())
+    }
+
+    fn buffered(&self) -> usize {
+        self.0.in_progress_size()
+    }
+
+    fn flush(&mut self) -> Result<(), ProcessingError> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ProcessingError> {
+        self.0.close()?;
+        Ok(())
+    }
+}
+
+struct IpcFileSink<W: Write> {
+    writer: FileWriter<W>,
+    schema: Arc<Schema>,
+}
+
+impl<W: Write> Sink for IpcFileSink<W> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        let columns = (batch.columns().iter())
+            .map(|column| match column.data_type() {
+                DataType::Dictionary(_, values) => cast(column, values),
+                _ => Ok(column.clone()),
+            })
+            .collect::<Result<Vec<ArrayRef>, _>>()?;
+        self.writer
+            .write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+        Ok(())
+    }
+
+    fn close(mut self: Box<Self>) -> Result<(), ProcessingError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+struct IpcStreamSink<W: Write> {
+    writer: StreamWriter<W>,
+    schema: Arc<Schema>,
+}
+
+impl<W: Write> Sink for IpcStreamSink<W> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        // The writer goes by the dictionary ids in the schema of the batch
+        let batch = RecordBatch::try_new(self.schema.clone(), batch.columns().to_vec())?;
+        self.writer.write(&batch)?;
+        Ok(())
+    }
+
+    fn close(mut self: Box<Self>) -> Result<(), ProcessingError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+struct JsonSink<W: Write>(LineDelimitedWriter<W>);
+
+impl<W: Write> Sink for JsonSink<W> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        let mut rows = record_batches_to_json_rows(&[batch])?;
+        // A null struct comes out as an object of whatever its fields hold, so
+        // it is left out here, the same as any other null, as are those in
+        // the lists and structs of the row
+        for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+            if let DataType::Struct(_) | DataType::List(_) = column.data_type() {
+                for (row, object) in rows.iter_mut().enumerate() {
+                    if column.is_null(row) {
+                        object.remove(field.name());
+                    } else if let Some(value) = object.get_mut(field.name()) {
+                        clear_null_structs(column, row, value);
+                    }
+                }
+            }
+        }
+        for row in rows {
+            self.0.write_row(&row.into())?;
+        }
+        Ok(())
+    }
+
+    fn close(mut self: Box<Self>) -> Result<(), ProcessingError> {
+        self.0.finish()?;
+        Ok(())
+    }
+}
+
+struct CsvSink<W: Write> {
+    writer: csv::Writer<W>,
+    separator: String,
+}
+
+impl<W: Write> Sink for CsvSink<W> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        let mut columns = Vec::new();
+        for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+            flatten(
+                field.name(),
+                column,
+                None,
+                &self.separator,
+                false,
+                &mut columns,
+            )?;
+        }
+        let (fields, columns): (Vec<_>, Vec<_>) = (columns.into_iter())
+            .map(|(name, column)| (Field::new(name, column.data_type().clone(), true), column))
+            .unzip();
+        let schema = Arc::new(Schema::new(fields));
+        self.writer.write(&RecordBatch::try_new(schema, columns)?)?;
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ProcessingError> {
+        self.writer.into_inner().flush()?;
+        Ok(())
+    }
+}
+
+/// Flatten a column for CSV. Structs become a column for each of their
+/// fields, named by its path, e.g. `labels.cat_no`, and lists become a column
+/// of their items joined by `separator`. Every item has a place in the list,
+/// even when null, so the n-th `labels.id` goes with the n-th `labels.cat_no`.
+/// Lists in lists couldn't be told apart from the list they're in that way, so
+/// they're written as JSON instead, e.g. `tracklist.artists` is an array with
+/// the artists of each track.
+fn flatten(
+    name: &str,
+    column: &ArrayRef,
+    nulls: Option<&NullBuffer>,
+    separator: &str,
+    in_list: bool,
+    columns: &mut Vec<(String, ArrayRef)>,
+) -> Result<(), ArrowError> {
+    // The values under a null struct or list aren't necessarily null
+    let nulls = NullBuffer::union(nulls, column.nulls());
+    match column.data_type() {
+        DataType::Struct(fields) => {
+            let column = column.as_struct();
+            for (field, child) in fields.iter().zip(column.columns()) {
+                let name = format!("{name}.{}", field.name());
+                flatten(&name, child, nulls.as_ref(), separator, in_list, columns)?;
+            }
+        }
+        // Lists in lists are left as they are, for `join_json`
+        DataType::List(_) if !in_list => {
+            let list = column.as_list::<i32>();
+            let mut items = Vec::new();
+            flatten(name, list.values(), None, separator, true, &mut items)?;
+            for (name, values) in items {
+                let joined = match values.data_type() {
+                    DataType::List(_) => join_json(list, &values, nulls.as_ref())?,
+                    _ => join(list, &values, nulls.as_ref(), separator)?,
+                };
+                columns.push((name, joined));
+            }
+        }
+        _ => {
+            let column = match nulls {
+                Some(nulls) => nullif(column, &BooleanArray::new(!nulls.inner(), None))?,
+                None => column.clone(),
+            };
+            columns.push((name.to_owned(), column));
+        }
+    }
+    Ok(())
+}
+
+/// The items of each list, written out and joined by `separator`.
+fn join(
+    list: &ListArray,
+    values: &ArrayRef,
+    nulls: Option<&NullBuffer>,
+    separator: &str,
+) -> Result<ArrayRef, ArrowError> {
+    let formatter = ArrayFormatter::try_new(values, &FormatOptions::default())?;
+    let mut joined = StringBuilder::new();
+    let mut items = String::new();
+
+    for (row, range) in list.value_offsets().windows(2).enumerate() {
+        if nulls.is_some_and(|nulls| nulls.is_null(row)) {
+            joined.append_null();
+            continue;
+        }
+        let (start, end) = (range[0] as usize, range[1] as usize);
+        items.clear();
+        for i in start..end {
+            if i > start {
+                items.push_str(separator);
+            }
+            write!(items, "{}", formatter.value(i)).unwrap();
+        }
+        joined.append_value(&items);
+    }
+
+    Ok(Arc::new(joined.finish()))
+}
+
+/// The items of each list as a JSON array, for items that are lists too.
+fn join_json(
+    list: &ListArray,
+    values: &ArrayRef,
+    nulls: Option<&NullBuffer>,
+) -> Result<ArrayRef, ArrowError> {
+    let values = json_values(values)?;
+    let mut joined = StringBuilder::new();
+
+    for (row, range) in list.value_offsets().windows(2).enumerate() {
+        if nulls.is_some_and(|nulls| nulls.is_null(row)) {
+            joined.append_null();
+            continue;
+        }
+        let items = &values[range[0] as usize..range[1] as usize];
+        joined.append_value(JsonValue::Array(items.to_vec()).to_string());
+    }
+
+    Ok(Arc::new(joined.finish()))
+}
+
+/// The values of a column as JSON, with nulls for null structs, rather than
+/// objects of whatever their fields hold.
+fn json_values(column: &ArrayRef) -> Result<Vec<JsonValue>, ArrowError> {
+    let mut values = array_to_json_array(column)?;
+    for (row, value) in values.iter_mut().enumerate() {
+        if column.is_null(row) {
+            *value = JsonValue::Null;
+        } else {
+            clear_null_structs(column, row, value);
+        }
+    }
+    Ok(values)
+}
+
+/// Take the null structs out of a value, as it is written to JSON. They're
+/// left out of the object they're in, like any other null field, and are null
+/// in lists, so that the items after them keep their places.
+fn clear_null_structs(column: &ArrayRef, row: usize, value: &mut JsonValue) {
+    match (column.data_type(), value) {
+        (DataType::Struct(fields), JsonValue::Object(object)) => {
+            let column = column.as_struct();
+            for (field, child) in fields.iter().zip(column.columns()) {
+                if child.is_null(row) {
+                    object.remove(field.name());
+                } else if let Some(value) = object.get_mut(field.name()) {
+                    clear_null_structs(child, row, value);
+                }
+            }
+        }
+        (DataType::List(_), JsonValue::Array(items)) => {
+            let list = column.as_list::<i32>();
+            let start = list.value_offsets()[row] as usize;
+            for (i, item) in items.iter_mut().enumerate() {
+                if list.values().is_null(start + i) {
+                    *item = JsonValue::Null;
+                } else {
+                    clear_null_structs(list.values(), start + i, item);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+struct SqliteSink {
+    connection: Connection,
+    table: String,
+    insert: String,
+    /// The columns to index once all of the rows are in
+    keys: Vec<String>,
+}
+
+impl SqliteSink {
+    fn open(
+        path: &Path,
+        table: &str,
+        schema: &Schema,
+        options: &OutputOptions,
+    ) -> Result<Self, ProcessingError> {
+        let connection = Connection::open(path)?;
+        // A database that is only half written is no use anyway, so there's
+        // no point waiting for each transaction to reach the disk
+        connection.pragma_update(None, "synchronous", "OFF")?;
+        connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+
+        let columns: Vec<_> = (schema.fields().iter())
+            .map(|field| {
+                let not_null = if field.is_nullable() { "" } else { " NOT NULL" };
+                let sql_type = sql_type(field.data_type());
+                format!("{} {sql_type}{not_null}", quote(field.name()))
+            })
+            .collect();
+        connection.execute_batch(&format!(
+            "DROP TABLE IF EXISTS {table};\nCREATE TABLE {table} ({columns});",
+            table = quote(table),
+            columns = columns.join(", "),
+        ))?;
+
+        let params = vec!["?"; columns.len()].join(", ");
+        let keys = match options.layout {
+            Layout::Nested => Vec::new(),
+            Layout::Normalized => normalize::keys(schema)
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+        };
+        Ok(SqliteSink {
+            connection,
+            table: table.to_owned(),
+            insert: format!("INSERT INTO {} VALUES ({params})", quote(table)),
+            keys,
+        })
+    }
+}
+
+impl Sink for SqliteSink {
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ProcessingError> {
+        let columns = (batch.columns().iter())
+            .map(sql_values)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let transaction = self.connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(&self.insert)?;
+            for row in 0..batch.num_rows() {
+                insert.execute(params_from_iter(columns.iter().map(|column| &column[row])))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), ProcessingError> {
+        for key in &self.keys {
+            self.connection.execute_batch(&format!(
+                "CREATE INDEX {} ON {} ({});",
+                quote(&format!("{}_{key}", self.table)),
+                quote(&self.table),
+                quote(key),
+            ))?;
+        }
+        self.connection.close().map_err(|(_, err)| err)?;
+        Ok(())
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_type(data_type: &DataType) -> &'static str {
+    match data_type {
+        DataType::Boolean => "INTEGER",
+        data_type if data_type.is_integer() => "INTEGER",
+        DataType::List(_) | DataType::Struct(_) => "JSON",
+        _ => "TEXT",
+    }
+}
+
+/// The values of a column as SQLite has them. Booleans are 0 or 1, dates are
+/// text, and lists and structs are JSON, which SQLite has functions for.
+fn sql_values(column: &ArrayRef) -> Result<Vec<Value>, ArrowError> {
+    let values = match column.data_type() {
+        DataType::List(_) | DataType::Struct(_) => (json_values(column)?.into_iter())
+            .map(|value| match value {
+                JsonValue::Null => Value::Null,
+                value => Value::Text(value.to_string()),
+            })
+            .collect(),
+        data_type if data_type.is_integer() || *data_type == DataType::Boolean => {
+            let integers = cast(column, &DataType::Int64)?;
+            (integers.as_primitive::<Int64Type>().iter())
+                .map(|value| value.map_or(Value::Null, Value::Integer))
+                .collect()
+        }
+        _ => {
+            let text = cast(column, &DataType::Utf8)?;
+            (text.as_string::<i32>().iter())
+                .map(|value| value.map_or(Value::Null, |value| Value::Text(value.to_owned())))
+                .collect()
+        }
+    };
+    Ok(values)
+}